@@ -0,0 +1,103 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use log::info;
+use uefi::{Error, Result, Status};
+
+use crate::getvar::VariableProvider;
+use crate::partition;
+
+const MISC_PARTITION: &str = "misc";
+
+pub(crate) const BCB_BOOT_RECOVERY: &str = "boot-recovery";
+pub(crate) const BCB_BOOT_FASTBOOT: &str = "boot-fastboot";
+
+#[repr(C)]
+struct BootloaderMessage {
+    command: [u8; 32],
+    status: [u8; 32],
+    recovery: [u8; 768],
+    stage: [u8; 32],
+    reserved: [u8; 1184],
+}
+
+fn field_to_string(field: &[u8]) -> String {
+    let field = field.split(|c| *c == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(field).into_owned()
+}
+
+// The bootloader message only covers the start of the first block(s) of
+// misc, so it's read and written back along with the rest of those blocks.
+fn read_misc() -> Result<Vec<u8>, &'static str> {
+    let misc = partition::find_partition(MISC_PARTITION)?;
+    let len = size_of::<BootloaderMessage>().next_multiple_of(misc.block_size());
+
+    let mut blocks = vec![0u8; len];
+    misc.read(0, &mut blocks)?;
+
+    Ok(blocks)
+}
+
+pub(crate) fn read_command() -> Result<Option<String>, &'static str> {
+    let blocks = read_misc()?;
+    let message: BootloaderMessage = unsafe { ptr::read_unaligned(blocks.as_ptr().cast()) };
+
+    let command = field_to_string(&message.command);
+    Ok((!command.is_empty()).then_some(command))
+}
+
+pub(crate) fn write_command(command: &str) -> Result<(), &'static str> {
+    let mut blocks = read_misc()?;
+    let mut message: BootloaderMessage = unsafe { ptr::read_unaligned(blocks.as_ptr().cast()) };
+
+    if command.len() >= message.command.len() {
+        return Err(Error::new(
+            Status::INVALID_PARAMETER,
+            "bcb command too long",
+        ));
+    }
+
+    message.command = [0; 32];
+    message.command[..command.len()].copy_from_slice(command.as_bytes());
+    message.status = [0; 32];
+
+    unsafe { ptr::write_unaligned(blocks.as_mut_ptr().cast(), message) };
+
+    let misc = partition::find_partition(MISC_PARTITION)?;
+    let mut writer = misc.open()?;
+    writer.write(0, &blocks)?;
+    writer.flush()
+}
+
+pub(crate) struct BcbVariables {
+    command: Option<String>,
+}
+
+impl BcbVariables {
+    pub(crate) fn new() -> Self {
+        let command = read_command().unwrap_or(None);
+        if let Some(command) = &command {
+            info!("pending bootloader message: {command}");
+        }
+
+        Self { command }
+    }
+}
+
+impl VariableProvider for BcbVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "bcb-command" => Some(self.command.clone().unwrap_or_default()),
+            _ => None,
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        vec![("bcb-command".into(), self.get("bcb-command").unwrap())]
+    }
+}
@@ -0,0 +1,526 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use log::info;
+
+use crate::download::{Download, DownloadAllocator};
+use crate::error::{FastbootError, FastbootResult};
+use crate::getvar::Variables;
+use crate::reboot::RebootTarget;
+use crate::transport::FastbootTransport;
+
+pub(crate) struct Context<'a> {
+    pub(crate) transport: &'a dyn FastbootTransport,
+    pub(crate) allocator: &'a dyn DownloadAllocator,
+    pub(crate) variables: &'a Variables,
+    pub(crate) loaded_data: Option<Download>,
+    /// Data put aside by the previous command for `upload` to send.
+    pub(crate) staged: Option<Vec<u8>>,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn respond(&self, response: &str) -> FastbootResult {
+        self.transport.respond(response)
+    }
+
+    fn loaded_data(&self) -> FastbootResult<&[u8]> {
+        self.loaded_data
+            .as_ref()
+            .map(Download::as_slice)
+            .ok_or(FastbootError::NoDownload)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Action {
+    Continue,
+    Exit,
+}
+
+pub(crate) trait FastbootCommand {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    fn execute(&self, context: &mut Context, args: Option<&str>) -> FastbootResult<Action>;
+}
+
+pub(crate) fn required(args: Option<&str>) -> FastbootResult<&str> {
+    args.filter(|args| !args.is_empty())
+        .ok_or(FastbootError::MissingArgument)
+}
+
+type CommandFn = fn(&mut Context, Option<&str>) -> FastbootResult<Action>;
+
+struct Command {
+    name: &'static str,
+    description: &'static str,
+    handler: CommandFn,
+}
+
+impl FastbootCommand for Command {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn execute(&self, context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+        (self.handler)(context, args)
+    }
+}
+
+struct RebootCommand {
+    name: &'static str,
+    description: &'static str,
+    target: RebootTarget,
+}
+
+impl FastbootCommand for RebootCommand {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn execute(&self, context: &mut Context, _args: Option<&str>) -> FastbootResult<Action> {
+        crate::handle_reboot(context.transport, self.target)?;
+        Ok(Action::Continue)
+    }
+}
+
+fn download(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    let size = match usize::from_str_radix(required(args)?, 16) {
+        Ok(size) if size > 0 => size,
+        _ => return Err(FastbootError::InvalidArgument("download size")),
+    };
+
+    // Frees the previous download before the new one is sized.
+    context.loaded_data = None;
+    context.loaded_data = Some(crate::handle_download(
+        context.transport,
+        context.allocator,
+        size,
+    )?);
+    Ok(Action::Continue)
+}
+
+fn boot(context: &mut Context, _args: Option<&str>) -> FastbootResult<Action> {
+    let payload = context.loaded_data()?;
+
+    crate::handle_boot(context.transport, payload)?;
+    Ok(Action::Continue)
+}
+
+fn flash(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    let partition = required(args)?;
+    let payload = context.loaded_data()?;
+
+    crate::handle_flash(context.transport, partition, payload)?;
+    Ok(Action::Continue)
+}
+
+fn erase(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    crate::handle_erase(context.transport, required(args)?)?;
+    Ok(Action::Continue)
+}
+
+fn upload(context: &mut Context, _args: Option<&str>) -> FastbootResult<Action> {
+    let payload = context
+        .staged
+        .as_deref()
+        .ok_or(FastbootError::NothingStaged)?;
+
+    crate::handle_upload(context.transport, payload)?;
+    Ok(Action::Continue)
+}
+
+fn fetch(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    crate::handle_fetch(context.transport, required(args)?)?;
+    Ok(Action::Continue)
+}
+
+fn set_active(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    crate::handle_set_active(context.transport, required(args)?)?;
+    Ok(Action::Continue)
+}
+
+fn getvar(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    crate::handle_getvar(context.transport, context.variables, required(args)?)?;
+    Ok(Action::Continue)
+}
+
+fn read_memory(context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+    context.staged = Some(crate::handle_read_memory(required(args)?)?);
+    context.respond("OKAY")?;
+    Ok(Action::Continue)
+}
+
+fn continue_boot(context: &mut Context, _args: Option<&str>) -> FastbootResult<Action> {
+    context.respond("OKAY")?;
+    context.transport.flush()?;
+    Ok(Action::Exit)
+}
+
+pub(crate) struct Commands<'a> {
+    commands: Vec<Box<dyn FastbootCommand + 'a>>,
+    oem_commands: Vec<Box<dyn FastbootCommand + 'a>>,
+}
+
+impl<'a> Commands<'a> {
+    pub(crate) fn new() -> Self {
+        let mut commands = Self {
+            commands: Vec::new(),
+            oem_commands: Vec::new(),
+        };
+
+        let standard: [(&'static str, &'static str, CommandFn); 9] = [
+            ("download", "download:<size> - receive data", download),
+            ("boot", "boot - boot the downloaded image", boot),
+            ("flash", "flash:<partition> - write downloaded data", flash),
+            ("erase", "erase:<partition> - erase a partition", erase),
+            ("upload", "upload - send staged data to the host", upload),
+            (
+                "fetch",
+                "fetch:<partition>:<offset>:<size> - read a partition",
+                fetch,
+            ),
+            (
+                "set_active",
+                "set_active:<slot> - select the slot to boot",
+                set_active,
+            ),
+            ("getvar", "getvar:<variable> - read a variable", getvar),
+            (
+                "continue",
+                "continue - exit and continue booting",
+                continue_boot,
+            ),
+        ];
+        for (name, description, handler) in standard {
+            commands.register(Box::new(Command {
+                name,
+                description,
+                handler,
+            }));
+        }
+
+        let reboot: [(&'static str, &'static str, RebootTarget); 6] = [
+            ("reboot", "reboot - reboot the device", RebootTarget::Normal),
+            (
+                "reboot-bootloader",
+                "reboot-bootloader - reboot to the bootloader",
+                RebootTarget::Bootloader,
+            ),
+            (
+                "reboot-recovery",
+                "reboot-recovery - reboot to recovery",
+                RebootTarget::Recovery,
+            ),
+            (
+                "reboot-fastboot",
+                "reboot-fastboot - reboot to fastbootd",
+                RebootTarget::Fastboot,
+            ),
+            (
+                "reboot-edl",
+                "reboot-edl - reboot to emergency download",
+                RebootTarget::Edl,
+            ),
+            (
+                "powerdown",
+                "powerdown - power off the device",
+                RebootTarget::PowerOff,
+            ),
+        ];
+        for (name, description, target) in reboot {
+            commands.register(Box::new(RebootCommand {
+                name,
+                description,
+                target,
+            }));
+        }
+
+        commands.register_oem(Box::new(RebootCommand {
+            name: "poweroff",
+            description: "oem poweroff - power off the device",
+            target: RebootTarget::PowerOff,
+        }));
+        commands.register_oem(Box::new(RebootCommand {
+            name: "reboot-firmware",
+            description: "oem reboot-firmware - reboot to the firmware setup UI",
+            target: RebootTarget::FirmwareUi,
+        }));
+        commands.register_oem(Box::new(Command {
+            name: "read-memory",
+            description: "oem read-memory <address> <size> - stage memory for upload",
+            handler: read_memory,
+        }));
+
+        commands
+    }
+
+    pub(crate) fn register(&mut self, command: Box<dyn FastbootCommand + 'a>) {
+        self.commands.push(command);
+    }
+
+    pub(crate) fn register_oem(&mut self, command: Box<dyn FastbootCommand + 'a>) {
+        self.oem_commands.push(command);
+    }
+
+    fn find<'b>(
+        commands: &'b [Box<dyn FastbootCommand + 'a>],
+        name: &str,
+    ) -> Option<&'b dyn FastbootCommand> {
+        commands
+            .iter()
+            .find(|command| command.name() == name)
+            .map(|command| command.as_ref())
+    }
+
+    fn help(&self, context: &mut Context) -> FastbootResult<Action> {
+        for command in &self.commands {
+            context.respond(&format!("INFO{}", command.description()))?;
+        }
+        context.respond("INFOoem help - list available commands")?;
+        for command in &self.oem_commands {
+            context.respond(&format!("INFO{}", command.description()))?;
+        }
+
+        context.respond("OKAY")?;
+        Ok(Action::Continue)
+    }
+
+    fn execute(&self, context: &mut Context, request: &[u8]) -> FastbootResult<Action> {
+        let request = core::str::from_utf8(request).map_err(|_| FastbootError::InvalidRequest)?;
+
+        match request.strip_prefix("oem ") {
+            Some("help") => self.help(context),
+            Some(oem) => {
+                let (name, args) = match oem.split_once(' ') {
+                    Some((name, args)) => (name, Some(args.trim())),
+                    None => (oem, None),
+                };
+
+                match Self::find(&self.oem_commands, name) {
+                    Some(command) => command.execute(context, args),
+                    None => Err(FastbootError::UnknownCommand),
+                }
+            }
+            None => {
+                let (name, args) = match request.split_once(':') {
+                    Some((name, args)) => (name, Some(args)),
+                    None => (request, None),
+                };
+
+                match Self::find(&self.commands, name) {
+                    Some(command) => command.execute(context, args),
+                    None => Err(FastbootError::UnknownCommand),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn dispatch(&self, context: &mut Context, request: &[u8]) -> Action {
+        // Staged data is only kept for an upload right after the command
+        // that staged it.
+        if request != b"upload" {
+            context.staged = None;
+        }
+
+        match self.execute(context, request) {
+            Ok(action) => action,
+            Err(err) => {
+                info!("command failed: {err} ({:?})", err.status());
+                if context.transport.can_respond() {
+                    let _ = context.respond(&format!("FAIL{err}"));
+                }
+                Action::Continue
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::tests::HeapAllocator;
+    use crate::serial::{SerialNumber, SerialSource};
+    use crate::transport::tests::MemoryTransport;
+    use alloc::string::String;
+
+    /// Stages the current download, so it can be read back with `upload`.
+    struct StageCommand;
+
+    impl FastbootCommand for StageCommand {
+        fn name(&self) -> &str {
+            "stage"
+        }
+
+        fn description(&self) -> &str {
+            "oem stage - stage the download"
+        }
+
+        fn execute(&self, context: &mut Context, _args: Option<&str>) -> FastbootResult<Action> {
+            context.staged = Some(context.loaded_data()?.to_vec());
+            context.respond("OKAY")?;
+            Ok(Action::Continue)
+        }
+    }
+
+    fn run_with_download(commands: &[&[u8]], download: &[u8]) -> MemoryTransport {
+        let mut transport = MemoryTransport::new(commands);
+        transport.download = download.to_vec();
+        let allocator = HeapAllocator { max_size: 16 };
+        let variables = Variables::new(&SerialNumber {
+            value: "0123456789".into(),
+            source: SerialSource::Default,
+        });
+
+        let mut commands = Commands::new();
+        commands.register_oem(Box::new(StageCommand));
+        let mut context = Context {
+            transport: &transport,
+            allocator: &allocator,
+            variables: &variables,
+            loaded_data: None,
+            staged: None,
+        };
+        while let Ok(request) = transport.receive_command() {
+            commands.dispatch(&mut context, request);
+        }
+
+        transport
+    }
+
+    fn run(commands: &[&[u8]]) -> MemoryTransport {
+        run_with_download(commands, &[])
+    }
+
+    #[test]
+    fn commands_run_without_usb() {
+        let transport = run(&[b"getvar:serialno", b"upload", b"frobnicate"]);
+
+        assert_eq!(
+            transport.responses(),
+            [
+                "OKAY0123456789",
+                "FAILnothing staged for upload",
+                "FAILunknown command",
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_sized_download_is_rejected() {
+        let transport = run(&[b"download:00000000"]);
+
+        assert_eq!(transport.responses(), ["FAILinvalid download size"]);
+    }
+
+    #[test]
+    fn oversized_download_is_rejected() {
+        let transport = run(&[b"download:00000011"]);
+
+        assert_eq!(transport.responses(), ["FAILdownload size too large"]);
+    }
+
+    #[test]
+    fn download_round_trips_through_upload() {
+        let transport =
+            run_with_download(&[b"download:00000005", b"oem stage", b"upload"], b"hello");
+
+        assert_eq!(
+            transport.responses(),
+            ["DATA00000005", "OKAY", "OKAY", "DATA00000005", "OKAY"]
+        );
+        assert_eq!(*transport.upload.borrow(), b"hello");
+    }
+
+    #[test]
+    fn upload_sends_staged_data_until_the_next_command() {
+        let transport = run_with_download(
+            &[
+                b"download:00000006",
+                b"oem stage",
+                b"upload",
+                b"upload",
+                b"getvar:serialno",
+                b"upload",
+            ],
+            b"staged",
+        );
+
+        assert_eq!(
+            transport.responses(),
+            [
+                "DATA00000006",
+                "OKAY",
+                "OKAY",
+                "DATA00000006",
+                "OKAY",
+                "DATA00000006",
+                "OKAY",
+                "OKAY0123456789",
+                "FAILnothing staged for upload",
+            ]
+        );
+        assert_eq!(*transport.upload.borrow(), b"stagedstaged");
+    }
+
+    #[test]
+    fn flash_needs_a_partition_and_a_download() {
+        let transport = run(&[b"flash", b"flash:", b"flash:boot"]);
+
+        assert_eq!(
+            transport.responses(),
+            [
+                "FAILmissing argument",
+                "FAILmissing argument",
+                "FAILdownload something first",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_arguments_fail() {
+        let transport = run(&[
+            b"download",
+            b"erase",
+            b"fetch",
+            b"set_active",
+            b"getvar",
+            b"oem read-memory",
+        ]);
+
+        assert_eq!(transport.responses(), ["FAILmissing argument"; 6]);
+    }
+
+    #[test]
+    fn oem_help_lists_every_command() {
+        let transport = run(&[b"oem help"]);
+        let responses = transport.responses();
+
+        let (last, lines) = responses.split_last().unwrap();
+        assert_eq!(last, "OKAY");
+        assert!(lines.iter().all(|line| line.starts_with("INFO")));
+
+        let lines: Vec<String> = lines.iter().map(|line| line[4..].into()).collect();
+        for expected in [
+            "download:<size> - receive data",
+            "upload - send staged data to the host",
+            "continue - exit and continue booting",
+            "powerdown - power off the device",
+            "oem help - list available commands",
+            "oem read-memory <address> <size> - stage memory for upload",
+            "oem stage - stage the download",
+        ] {
+            assert!(lines.iter().any(|line| line == expected), "{expected}");
+        }
+    }
+}
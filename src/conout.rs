@@ -0,0 +1,115 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::collections::VecDeque;
+use core::cell::{Cell, RefCell};
+use uefi::table;
+use uefi_raw::protocol::console::SimpleTextOutputProtocol;
+use uefi_raw::{Char16, Status};
+
+type OutputString =
+    unsafe extern "efiapi" fn(this: *mut SimpleTextOutputProtocol, string: *const Char16) -> Status;
+
+const MAX_MIRROR_SIZE: usize = 64 * 1024;
+
+struct Mirror {
+    output_string: Cell<Option<OutputString>>,
+    buffer: RefCell<VecDeque<u8>>,
+}
+
+// Boot services run on a single processor without preemption of the
+// application, so the cells are never touched concurrently.
+unsafe impl Sync for Mirror {}
+
+static MIRROR: Mirror = Mirror {
+    output_string: Cell::new(None),
+    buffer: RefCell::new(VecDeque::new()),
+};
+
+unsafe extern "efiapi" fn mirror_output_string(
+    this: *mut SimpleTextOutputProtocol,
+    string: *const Char16,
+) -> Status {
+    if !string.is_null() {
+        let string = string.cast::<u16>();
+        let len = (0..)
+            .take_while(|&i| unsafe { *string.add(i) } != 0)
+            .count();
+        let units = unsafe { core::slice::from_raw_parts(string, len) };
+
+        // Output produced while the buffer is being drained is dropped
+        // rather than recursing into it.
+        if let Ok(mut buffer) = MIRROR.buffer.try_borrow_mut() {
+            for c in char::decode_utf16(units.iter().copied()) {
+                let mut utf8 = [0; 4];
+                let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+                buffer.extend(c.encode_utf8(&mut utf8).as_bytes());
+            }
+
+            let excess = buffer.len().saturating_sub(MAX_MIRROR_SIZE);
+            buffer.drain(..excess);
+        }
+    }
+
+    match MIRROR.output_string.get() {
+        Some(output_string) => unsafe { output_string(this, string) },
+        None => Status::SUCCESS,
+    }
+}
+
+fn stdout() -> Option<*mut SimpleTextOutputProtocol> {
+    let system_table = table::system_table_raw()?;
+    let stdout = unsafe { system_table.as_ref() }.stdout;
+
+    (!stdout.is_null()).then_some(stdout)
+}
+
+/// Copies everything written to ConOut into a buffer, for as long as it
+/// lives. The uefi logger writes through ConOut as well, so log output is
+/// captured along with it.
+///
+/// The protocol instance is patched in place rather than replaced in the
+/// system table, as the logger holds on to the pointer it was set up with.
+pub(crate) struct ConOutMirror(());
+
+impl ConOutMirror {
+    pub(crate) fn install() -> Option<Self> {
+        let stdout = stdout()?;
+        if MIRROR.output_string.get().is_some() {
+            return None;
+        }
+
+        unsafe {
+            MIRROR.output_string.set(Some((*stdout).output_string));
+            (*stdout).output_string = mirror_output_string;
+        }
+
+        Some(Self(()))
+    }
+
+    /// Moves as much of the buffered output as fits into `buf`.
+    pub(crate) fn read(&self, buf: &mut [u8]) -> usize {
+        let mut buffer = MIRROR.buffer.borrow_mut();
+
+        let len = buf.len().min(buffer.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.drain(..len)) {
+            *dst = src;
+        }
+
+        len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        MIRROR.buffer.borrow().is_empty()
+    }
+}
+
+impl Drop for ConOutMirror {
+    fn drop(&mut self) {
+        if let (Some(stdout), Some(output_string)) = (stdout(), MIRROR.output_string.take()) {
+            unsafe { (*stdout).output_string = output_string };
+        }
+
+        MIRROR.buffer.borrow_mut().clear();
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::ptr::NonNull;
+use core::slice;
+use uefi::boot::{self, MemoryType};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::Result;
+
+use crate::error::FastbootResult;
+
+const DOWNLOAD_SIZE_MARGIN: usize = 64 * 1024 * 1024;
+
+pub(crate) fn max_download_size() -> Result<usize> {
+    let memory_map = boot::memory_map(MemoryType::LOADER_DATA)?;
+
+    let largest_free_region = memory_map
+        .entries()
+        .filter(|desc| desc.ty == MemoryType::CONVENTIONAL)
+        .map(|desc| desc.page_count as usize * boot::PAGE_SIZE)
+        .max()
+        .unwrap_or(0);
+
+    Ok(largest_free_region.saturating_sub(DOWNLOAD_SIZE_MARGIN))
+}
+
+/// A completed download, kept until it's replaced.
+pub(crate) struct Download {
+    ptr: NonNull<u8>,
+    len: usize,
+    free: unsafe fn(NonNull<u8>, usize),
+}
+
+impl Download {
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` writable bytes that stay valid until `free`
+    /// is called with them.
+    pub(crate) unsafe fn new(
+        ptr: NonNull<u8>,
+        len: usize,
+        free: unsafe fn(NonNull<u8>, usize),
+    ) -> Self {
+        Self { ptr, len, free }
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        unsafe { (self.free)(self.ptr, self.len) };
+    }
+}
+
+/// Where downloads are received into.
+pub(crate) trait DownloadAllocator {
+    /// The largest download that can be allocated right now.
+    fn max_download_size(&self) -> FastbootResult<usize>;
+
+    fn allocate(&self, size: usize) -> FastbootResult<Download>;
+}
+
+/// Allocates downloads from the firmware's pool, which isn't bound by the
+/// size of the heap.
+pub(crate) struct PoolAllocator;
+
+impl DownloadAllocator for PoolAllocator {
+    fn max_download_size(&self) -> FastbootResult<usize> {
+        Ok(max_download_size()?)
+    }
+
+    fn allocate(&self, size: usize) -> FastbootResult<Download> {
+        unsafe fn free(ptr: NonNull<u8>, _len: usize) {
+            let _ = unsafe { boot::free_pool(ptr) };
+        }
+
+        let ptr = boot::allocate_pool(MemoryType::BOOT_SERVICES_DATA, size)?;
+        Ok(unsafe { Download::new(ptr, size, free) })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use core::ptr;
+
+    /// Allocates downloads from the heap, up to a fixed size.
+    pub(crate) struct HeapAllocator {
+        pub(crate) max_size: usize,
+    }
+
+    impl DownloadAllocator for HeapAllocator {
+        fn max_download_size(&self) -> FastbootResult<usize> {
+            Ok(self.max_size)
+        }
+
+        fn allocate(&self, size: usize) -> FastbootResult<Download> {
+            unsafe fn free(ptr: NonNull<u8>, len: usize) {
+                drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr.as_ptr(), len)) });
+            }
+
+            let buffer = Box::into_raw(vec![0u8; size].into_boxed_slice());
+            let ptr = NonNull::new(buffer.cast::<u8>()).unwrap();
+            Ok(unsafe { Download::new(ptr, size, free) })
+        }
+    }
+}
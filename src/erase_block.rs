@@ -0,0 +1,25 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::ptr;
+use uefi::{proto::unsafe_protocol, Result, StatusExt};
+
+use crate::proto::erase_block::EraseBlockProtocol;
+
+#[derive(Debug)]
+#[repr(transparent)]
+#[unsafe_protocol(EraseBlockProtocol::GUID)]
+pub struct EraseBlock(EraseBlockProtocol);
+
+impl EraseBlock {
+    fn as_ffi_ptr(&self) -> *const EraseBlockProtocol {
+        let ptr: *const Self = self;
+        ptr.cast::<EraseBlockProtocol>()
+    }
+
+    pub fn erase_blocks(&self, media_id: u32, lba: u64, size: usize) -> Result {
+        let this = self.as_ffi_ptr();
+
+        unsafe { (self.0.erase_blocks)(this, media_id, lba, ptr::null_mut(), size) }.to_result()
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::string::String;
+use core::fmt;
+use uefi::Status;
+
+#[derive(Debug)]
+pub(crate) enum FastbootError {
+    InvalidRequest,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument(&'static str),
+    UnknownVariable(String),
+    NoDownload,
+    NothingStaged,
+    DownloadTooLarge,
+    OutOfResources,
+    Disconnected,
+    TransferFailed,
+    Firmware(Status),
+    Failed(Status, &'static str),
+}
+
+impl FastbootError {
+    pub(crate) fn status(&self) -> Status {
+        match self {
+            Self::InvalidRequest
+            | Self::MissingArgument
+            | Self::InvalidArgument(_)
+            | Self::DownloadTooLarge => Status::INVALID_PARAMETER,
+            Self::UnknownCommand | Self::UnknownVariable(_) => Status::NOT_FOUND,
+            Self::NoDownload | Self::NothingStaged => Status::NOT_READY,
+            Self::OutOfResources => Status::OUT_OF_RESOURCES,
+            Self::Disconnected => Status::ABORTED,
+            Self::TransferFailed => Status::DEVICE_ERROR,
+            Self::Firmware(status) | Self::Failed(status, _) => *status,
+        }
+    }
+}
+
+impl fmt::Display for FastbootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRequest => write!(f, "invalid command"),
+            Self::UnknownCommand => write!(f, "unknown command"),
+            Self::MissingArgument => write!(f, "missing argument"),
+            Self::InvalidArgument(what) => write!(f, "invalid {what}"),
+            Self::UnknownVariable(name) => write!(f, "unknown variable: {name}"),
+            Self::NoDownload => write!(f, "download something first"),
+            Self::NothingStaged => write!(f, "nothing staged for upload"),
+            Self::DownloadTooLarge => write!(f, "download size too large"),
+            Self::OutOfResources => write!(f, "out of memory"),
+            Self::Disconnected => write!(f, "host disconnected"),
+            Self::TransferFailed => write!(f, "usb transfer failed"),
+            Self::Firmware(status) => write!(f, "firmware error: {status:?}"),
+            Self::Failed(_, reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl From<uefi::Error> for FastbootError {
+    fn from(err: uefi::Error) -> Self {
+        match err.status() {
+            Status::OUT_OF_RESOURCES => Self::OutOfResources,
+            status => Self::Firmware(status),
+        }
+    }
+}
+
+impl From<uefi::Error<&'static str>> for FastbootError {
+    fn from(err: uefi::Error<&'static str>) -> Self {
+        Self::Failed(err.status(), err.data())
+    }
+}
+
+pub(crate) type FastbootResult<T = ()> = core::result::Result<T, FastbootError>;
@@ -0,0 +1,219 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use uefi::boot;
+use uefi::runtime::{self, VariableVendor};
+use uefi::{cstr16, system};
+
+use crate::memcardinfo::MemCardInfo;
+use crate::partition::{self, Partition};
+use crate::serial::SerialNumber;
+use crate::slot::SlotVariables;
+
+pub(crate) trait VariableProvider {
+    fn get(&self, name: &str) -> Option<String>;
+    fn all(&self) -> Vec<(String, String)>;
+}
+
+pub(crate) struct Variables {
+    providers: Vec<Box<dyn VariableProvider>>,
+}
+
+impl Variables {
+    pub(crate) fn new(serial_number: &SerialNumber) -> Self {
+        let mut variables = Self {
+            providers: Vec::new(),
+        };
+
+        variables.register(Box::new(StaticVariables {
+            serial_number: serial_number.value.clone(),
+            serial_number_source: serial_number.source.name(),
+        }));
+        variables.register(Box::new(PlatformVariables));
+        variables.register(Box::new(MemCardInfoVariables));
+        variables.register(Box::new(PartitionVariables));
+        variables.register(Box::new(SlotVariables));
+
+        variables
+    }
+
+    pub(crate) fn register(&mut self, provider: Box<dyn VariableProvider>) {
+        self.providers.push(provider);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<String> {
+        self.providers
+            .iter()
+            .find_map(|provider| provider.get(name))
+    }
+
+    pub(crate) fn all(&self) -> Vec<(String, String)> {
+        self.providers
+            .iter()
+            .flat_map(|provider| provider.all())
+            .collect()
+    }
+}
+
+struct StaticVariables {
+    serial_number: String,
+    serial_number_source: &'static str,
+}
+
+impl VariableProvider for StaticVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "version" => Some("0.4".into()),
+            "version-bootloader" => Some(env!("BUILD_VERSION").into()),
+            "serialno" => Some(self.serial_number.clone()),
+            "serialno-source" => Some(self.serial_number_source.into()),
+            "is-userspace" => Some("no".into()),
+            _ => None,
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        [
+            "version",
+            "version-bootloader",
+            "serialno",
+            "serialno-source",
+            "is-userspace",
+        ]
+        .iter()
+        .filter_map(|name| Some((name.to_string(), self.get(name)?)))
+        .collect()
+    }
+}
+
+struct PlatformVariables;
+
+impl PlatformVariables {
+    fn secure_boot() -> bool {
+        let mut buf = [0u8; 1];
+        match runtime::get_variable(
+            cstr16!("SecureBoot"),
+            &VariableVendor::GLOBAL_VARIABLE,
+            &mut buf,
+        ) {
+            Ok((value, _)) => value == [1],
+            Err(_) => false,
+        }
+    }
+}
+
+impl VariableProvider for PlatformVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "firmware-vendor" => Some(system::firmware_vendor().to_string()),
+            "firmware-revision" => Some(format!("{:#x}", system::firmware_revision())),
+            "uefi-revision" => Some(system::uefi_revision().to_string()),
+            "secure" => Some(if Self::secure_boot() { "yes" } else { "no" }.into()),
+            "max-download-size" => {
+                Some(format!("{:#x}", crate::download::max_download_size().ok()?))
+            }
+            "max-fetch-size" => Some(format!("{:#x}", crate::MAX_FETCH_SIZE)),
+            _ => None,
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        [
+            "firmware-vendor",
+            "firmware-revision",
+            "uefi-revision",
+            "secure",
+            "max-download-size",
+            "max-fetch-size",
+        ]
+        .iter()
+        .filter_map(|name| Some((name.to_string(), self.get(name)?)))
+        .collect()
+    }
+}
+
+struct MemCardInfoVariables;
+
+impl MemCardInfoVariables {
+    const NAMES: [&str; 3] = [
+        "storage-type",
+        "storage-manufacturer-id",
+        "storage-rpmb-size",
+    ];
+}
+
+impl VariableProvider for MemCardInfoVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        // The protocol is opened exclusively, so don't touch it for
+        // variables that belong to someone else.
+        if !Self::NAMES.contains(&name) {
+            return None;
+        }
+
+        let handle = boot::get_handle_for_protocol::<MemCardInfo>().ok()?;
+        let memcardinfo = boot::open_protocol_exclusive::<MemCardInfo>(handle).ok()?;
+        let cardinfo = memcardinfo.get_card_info().ok()?;
+
+        match name {
+            "storage-type" => {
+                let card_type = cardinfo.card_type.split(|c| *c == 0).next()?;
+                Some(String::from_utf8_lossy(card_type).into_owned())
+            }
+            "storage-manufacturer-id" => Some(format!("{:#x}", cardinfo.manufacturer_id)),
+            "storage-rpmb-size" => Some(format!("{:#x}", cardinfo.rpmb_size)),
+            _ => None,
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        Self::NAMES
+            .iter()
+            .filter_map(|name| Some((name.to_string(), self.get(name)?)))
+            .collect()
+    }
+}
+
+struct PartitionVariables;
+
+impl PartitionVariables {
+    fn partition_variables(partition: &Partition) -> [(String, String); 2] {
+        [
+            (
+                format!("partition-size:{}", partition.name()),
+                format!("{:#x}", partition.size()),
+            ),
+            (
+                format!("partition-type:{}", partition.name()),
+                partition.fs_type().into(),
+            ),
+        ]
+    }
+}
+
+impl VariableProvider for PartitionVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        let (variable, name) = name.split_once(':')?;
+        let partition = partition::find_partition(name).ok()?;
+
+        match variable {
+            "partition-size" => Some(format!("{:#x}", partition.size())),
+            "partition-type" => Some(partition.fs_type().into()),
+            _ => None,
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        let Ok(partitions) = partition::partitions() else {
+            return vec![];
+        };
+
+        partitions
+            .iter()
+            .flat_map(Self::partition_variables)
+            .collect()
+    }
+}
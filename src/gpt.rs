@@ -0,0 +1,219 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use uefi::boot::{self, ScopedProtocol};
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::partition::{GptPartitionAttributes, GptPartitionEntry};
+use uefi::{Error, Guid, Handle, Result, Status};
+
+use crate::partition::get_protocol;
+use crate::UefiResultContext;
+
+const GPT_HEADER_SIGNATURE: u64 = u64::from_le_bytes(*b"EFI PART");
+const GPT_PRIMARY_HEADER_LBA: u64 = 1;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct GptHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    partition_entry_lba: u64,
+    number_of_partition_entries: u32,
+    size_of_partition_entry: u32,
+    partition_entry_array_crc32: u32,
+}
+
+// Keeps the complete header block, so that writing it back preserves any
+// bytes following the header.
+struct GptHeaderBlock {
+    lba: u64,
+    block: Vec<u8>,
+}
+
+impl GptHeaderBlock {
+    fn header(&self) -> GptHeader {
+        unsafe { ptr::read_unaligned(self.block.as_ptr().cast()) }
+    }
+
+    fn set_header(&mut self, header: GptHeader) {
+        unsafe { ptr::write_unaligned(self.block.as_mut_ptr().cast(), header) }
+    }
+}
+
+pub(crate) struct Gpt {
+    block_io: ScopedProtocol<BlockIO>,
+    primary: GptHeaderBlock,
+    entries: Vec<u8>,
+}
+
+impl Gpt {
+    fn read_blocks(block_io: &BlockIO, lba: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+        let block_size = block_io.media().block_size() as usize;
+        let mut buf = vec![0u8; len.next_multiple_of(block_size)];
+
+        block_io
+            .read_blocks(block_io.media().media_id(), lba, &mut buf)
+            .with_context("failed to read gpt")?;
+
+        Ok(buf)
+    }
+
+    fn read_header(block_io: &BlockIO, lba: u64) -> Result<GptHeaderBlock, &'static str> {
+        let block = Self::read_blocks(block_io, lba, size_of::<GptHeader>())?;
+        let header_block = GptHeaderBlock { lba, block };
+        let header = header_block.header();
+
+        if header.signature != GPT_HEADER_SIGNATURE
+            || (header.header_size as usize) < size_of::<GptHeader>()
+            || header.header_size as usize > header_block.block.len()
+            || (header.size_of_partition_entry as usize) < size_of::<GptPartitionEntry>()
+        {
+            return Err(Error::new(Status::VOLUME_CORRUPTED, "invalid gpt header"));
+        }
+
+        Ok(header_block)
+    }
+
+    fn entries_len(header: &GptHeader) -> usize {
+        header.number_of_partition_entries as usize * header.size_of_partition_entry as usize
+    }
+
+    fn read(disk: Handle) -> Result<Self, &'static str> {
+        let block_io = get_protocol::<BlockIO>(disk).with_context("failed to open disk")?;
+
+        let primary = Self::read_header(&block_io, GPT_PRIMARY_HEADER_LBA)?;
+        let header = primary.header();
+        let entries = Self::read_blocks(
+            &block_io,
+            header.partition_entry_lba,
+            Self::entries_len(&header),
+        )?;
+
+        Ok(Self {
+            block_io,
+            primary,
+            entries,
+        })
+    }
+
+    // Locates the GPT on the physical disk holding the partition with the
+    // given unique partition GUID.
+    pub(crate) fn find_disk(unique_guid: Guid) -> Result<(Self, usize), &'static str> {
+        let handles = boot::find_handles::<BlockIO>().with_context("failed to find disks")?;
+
+        for handle in handles {
+            let Ok(block_io) = get_protocol::<BlockIO>(handle) else {
+                continue;
+            };
+            if block_io.media().is_logical_partition() || !block_io.media().is_media_present() {
+                continue;
+            }
+            drop(block_io);
+
+            let Ok(gpt) = Self::read(handle) else {
+                continue;
+            };
+            if let Some(index) = gpt.find(unique_guid) {
+                return Ok((gpt, index));
+            }
+        }
+
+        Err(Error::new(Status::NOT_FOUND, "partition not found in gpt"))
+    }
+
+    fn entry(&self, index: usize) -> GptPartitionEntry {
+        let header = self.primary.header();
+        let offset = index * header.size_of_partition_entry as usize;
+
+        unsafe { ptr::read_unaligned(self.entries[offset..].as_ptr().cast()) }
+    }
+
+    fn find(&self, unique_guid: Guid) -> Option<usize> {
+        let header = self.primary.header();
+
+        (0..header.number_of_partition_entries as usize)
+            .find(|index| { self.entry(*index).unique_partition_guid } == unique_guid)
+    }
+
+    pub(crate) fn attributes(&self, index: usize) -> GptPartitionAttributes {
+        self.entry(index).attributes
+    }
+
+    pub(crate) fn set_attributes(&mut self, index: usize, attributes: GptPartitionAttributes) {
+        let header = self.primary.header();
+        let offset = index * header.size_of_partition_entry as usize;
+
+        let mut entry = self.entry(index);
+        entry.attributes = attributes;
+
+        unsafe { ptr::write_unaligned(self.entries[offset..].as_mut_ptr().cast(), entry) }
+    }
+
+    fn update_header(
+        header_block: &mut GptHeaderBlock,
+        entries_crc32: u32,
+    ) -> Result<(), &'static str> {
+        let mut header = header_block.header();
+        header.partition_entry_array_crc32 = entries_crc32;
+        header.header_crc32 = 0;
+        header_block.set_header(header);
+
+        header.header_crc32 =
+            boot::calculate_crc32(&header_block.block[..header.header_size as usize])
+                .with_context("failed to compute gpt crc")?;
+        header_block.set_header(header);
+
+        Ok(())
+    }
+
+    fn write_copy(
+        &mut self,
+        header_block: &GptHeaderBlock,
+        entries: &[u8],
+    ) -> Result<(), &'static str> {
+        let media_id = self.block_io.media().media_id();
+        let entry_lba = header_block.header().partition_entry_lba;
+
+        self.block_io
+            .write_blocks(media_id, entry_lba, entries)
+            .with_context("failed to write gpt")?;
+        self.block_io
+            .write_blocks(media_id, header_block.lba, &header_block.block)
+            .with_context("failed to write gpt")
+    }
+
+    pub(crate) fn write(mut self) -> Result<(), &'static str> {
+        let header = self.primary.header();
+        let entries = core::mem::take(&mut self.entries);
+
+        let entries_crc32 = boot::calculate_crc32(&entries[..Self::entries_len(&header)])
+            .with_context("failed to compute gpt crc")?;
+
+        let mut backup = Self::read_header(&self.block_io, header.alternate_lba)?;
+        Self::update_header(&mut backup, entries_crc32)?;
+        self.write_copy(&backup, &entries)?;
+
+        let mut primary = GptHeaderBlock {
+            lba: self.primary.lba,
+            block: core::mem::take(&mut self.primary.block),
+        };
+        Self::update_header(&mut primary, entries_crc32)?;
+        self.write_copy(&primary, &entries)?;
+
+        self.block_io
+            .flush_blocks()
+            .with_context("failed to flush gpt")
+    }
+}
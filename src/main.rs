@@ -1,36 +1,77 @@
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use alloc::{format, slice};
 use core::ffi::c_void;
 use core::ptr::{self, NonNull};
 use log::info;
 use uefi::boot::{EventType, MemoryType, ScopedProtocol, Tpl};
 use uefi::data_types::Event;
-use uefi::runtime::ResetType;
-use uefi::{guid, prelude::*, CStr16, CString16, Error, Guid, Result};
-
-use memcardinfo::MemCardInfo;
+use uefi::mem::memory_map::{MemoryAttribute, MemoryMap};
+use uefi::{guid, prelude::*, Error, Guid, Result};
 
 mod abootimg;
 use abootimg::{handle_bootimg_v0, handle_bootimg_v2, is_bootimg_v0, is_bootimg_v2};
 
+mod bcb;
+mod command;
+mod conout;
+use command::{Action, Commands, Context};
+
+use bcb::BcbVariables;
+
+mod download;
+use download::{Download, DownloadAllocator, PoolAllocator};
+
+mod erase_block;
+mod error;
+use error::{FastbootError, FastbootResult};
+
+mod getvar;
+mod gpt;
+use getvar::Variables;
+
 mod initrd;
 mod memcardinfo;
+mod partition;
 
 mod peimage;
 use peimage::{handle_peimage, is_peimage};
 
 mod proto;
 
+mod reboot;
+use reboot::RebootTarget;
+
+mod serial;
+mod slot;
+mod sparse;
+
+mod transport;
+use transport::FastbootTransport;
+
+mod ums;
+use ums::UmsCommand;
+
+mod usb_config;
+use usb_config::UsbConfig;
+
 mod usb_device;
+use usb_device::{EfiUsbDevice, UsbSession};
+
+mod usb_transport;
+use usb_transport::UsbTransport;
 
-use usb_device::EfiUsbDevice;
+const ERASE_PROGRESS_THRESHOLD: u64 = 256 * 1024 * 1024;
+const MAX_FETCH_SIZE: u64 = 0x4000_0000;
+const MAX_READ_MEMORY_SIZE: u64 = 64 * 1024 * 1024;
 
 const QCOM_INIT_USB_CONTROLLER_GUID: Guid = guid!("1c0cffce-fc8d-4e44-8c78-9c9e5b530d36");
 const EFI_RT_PROPERTIES_TABLE: Guid = guid!("eb66918a-7eef-402a-842e-931d21c38ae9");
@@ -65,91 +106,118 @@ fn signal_usb_controller_init() -> Result {
     Ok(())
 }
 
-fn fastboot_open(serial_number: &CStr16) -> Result<ScopedProtocol<EfiUsbDevice>> {
+fn fastboot_open() -> Result<ScopedProtocol<EfiUsbDevice>> {
     let handle = boot::get_handle_for_protocol::<EfiUsbDevice>()?;
     let usb_device = boot::open_protocol_exclusive::<EfiUsbDevice>(handle)?;
 
-    usb_device.start_ex(serial_number)?;
-
     Ok(usb_device)
 }
 
-fn fastboot_respond(usb_device: &ScopedProtocol<EfiUsbDevice>, response: &str) -> Result {
-    let buf = usb_device
-        .allocate_transfer_buffer(64)
-        .expect("failed to allocate command buffer");
+// The previous download must already be dropped, for its memory to count
+// towards the largest free region.
+fn handle_download(
+    transport: &dyn FastbootTransport,
+    allocator: &dyn DownloadAllocator,
+    size: usize,
+) -> FastbootResult<Download> {
+    if size > allocator.max_download_size()? {
+        return Err(FastbootError::DownloadTooLarge);
+    }
 
-    let mut payload = response.as_bytes().to_vec();
-    let payload_len = payload.len().min(64);
-    payload.push(0);
+    let mut download = allocator.allocate(size)?;
 
-    unsafe {
-        ptr::copy_nonoverlapping(payload.as_ptr(), buf, payload_len);
-    }
+    // A download cut short by a disconnect is discarded rather than handed
+    // back as if it were complete.
+    transport.respond(&format!("DATA{size:08x}"))?;
+    transport.receive_data(download.as_mut_slice())?;
 
-    usb_device
-        .send(usb_device::ENDPOINT_IN, payload_len, buf)
-        .expect("failed to send response");
+    transport.respond("OKAY")?;
 
-    Ok(())
+    Ok(download)
 }
 
-fn handle_download(usb_device: &ScopedProtocol<EfiUsbDevice>, size: usize) -> Result<&[u8]> {
-    let mut download_remains = size;
+fn send_data(
+    transport: &dyn FastbootTransport,
+    size: u64,
+    mut fill: impl FnMut(u64, &mut [u8]) -> FastbootResult,
+) -> FastbootResult {
+    transport.respond(&format!("DATA{size:08x}"))?;
+    transport.flush()?;
 
-    let target = boot::allocate_pool(MemoryType::BOOT_SERVICES_DATA, size).unwrap();
-    let target_slice = unsafe { slice::from_raw_parts_mut(target.as_ptr(), size) };
-    let mut offset = 0;
+    transport.send_data(size, &mut fill)
+}
 
-    let receive_buffer_size = size.min(16 * 1024 * 1024);
+fn handle_upload(transport: &dyn FastbootTransport, payload: &[u8]) -> FastbootResult {
+    send_data(transport, payload.len() as u64, |offset, buf| {
+        let offset = offset as usize;
+        buf.copy_from_slice(&payload[offset..offset + buf.len()]);
+        Ok(())
+    })?;
 
-    let receive_buffer = usb_device
-        .allocate_transfer_buffer(16 * 1024 * 1024)
-        .expect("failed to allocate command buffer");
+    transport.respond("OKAY")
+}
 
-    fastboot_respond(usb_device, &format!("DATA{size:08x}"))?;
+fn parse_hex(value: &str) -> Option<u64> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(value, 16).ok()
+}
 
-    usb_device
-        .send(
-            usb_device::ENDPOINT_OUT,
-            receive_buffer_size,
-            receive_buffer,
-        )
-        .expect("failed to queue command buffer");
+fn handle_fetch(transport: &dyn FastbootTransport, args: &str) -> FastbootResult {
+    let mut args = args.split(':');
+    let (Some(name), Some(offset), Some(size)) = (
+        args.next(),
+        args.next().and_then(parse_hex),
+        args.next().and_then(parse_hex),
+    ) else {
+        return Err(FastbootError::InvalidArgument("fetch arguments"));
+    };
 
-    loop {
-        let event = usb_device.handle_event().expect("handle_event failed");
-
-        match event {
-            usb_device::EfiUsbDeviceEvent::NoEvent => continue,
-            usb_device::EfiUsbDeviceEvent::Connected => todo!(),
-            usb_device::EfiUsbDeviceEvent::Disconnected => break,
-            usb_device::EfiUsbDeviceEvent::OutData(data) => {
-                let dest_slice = &mut target_slice[offset..offset + data.len()];
-                dest_slice.copy_from_slice(data);
-
-                download_remains -= data.len();
-                offset += data.len();
-
-                if offset == target_slice.len() {
-                    break;
-                }
-
-                let next_chunk = download_remains.min(receive_buffer_size);
-                usb_device
-                    .send(usb_device::ENDPOINT_OUT, next_chunk, receive_buffer)
-                    .expect("failed to queue command buffer");
-            }
-        }
+    let partition = partition::find_partition(name)?;
+
+    if size > MAX_FETCH_SIZE || offset.saturating_add(size) > partition.size() {
+        return Err(FastbootError::InvalidArgument("fetch range"));
     }
 
-    usb_device.free_transfer_buffer(receive_buffer)?;
+    send_data(transport, size, |pos, buf| {
+        Ok(partition.read(offset + pos, buf)?)
+    })?;
+
+    transport.respond("OKAY")
+}
+
+fn handle_read_memory(args: &str) -> FastbootResult<Vec<u8>> {
+    let mut args = args.split_whitespace();
+    let (Some(address), Some(size)) = (
+        args.next().and_then(parse_hex),
+        args.next().and_then(parse_hex),
+    ) else {
+        return Err(FastbootError::InvalidArgument("read-memory arguments"));
+    };
+
+    // Only ordinary RAM is read, so that the copy can't touch device
+    // registers or fault on an unmapped address.
+    let memory_map = boot::memory_map(MemoryType::LOADER_DATA)?;
+    let end = address.checked_add(size);
+    let mapped = memory_map.entries().any(|desc| {
+        let desc_end = desc.phys_start + desc.page_count * boot::PAGE_SIZE as u64;
+
+        desc.att.contains(MemoryAttribute::WRITE_BACK)
+            && !matches!(
+                desc.ty,
+                MemoryType::RESERVED
+                    | MemoryType::UNUSABLE
+                    | MemoryType::MMIO
+                    | MemoryType::MMIO_PORT_SPACE
+            )
+            && desc.phys_start <= address
+            && end.is_some_and(|end| end <= desc_end)
+    });
 
-    if offset == target_slice.len() {
-        fastboot_respond(usb_device, "OKAY")?;
+    if address == 0 || size == 0 || size > MAX_READ_MEMORY_SIZE || !mapped {
+        return Err(FastbootError::InvalidArgument("memory range"));
     }
 
-    Ok(target_slice)
+    Ok(unsafe { slice::from_raw_parts(address as *const u8, size as usize) }.to_vec())
 }
 
 struct FastbootBuffer {
@@ -160,7 +228,7 @@ struct FastbootBuffer {
 
 impl FastbootBuffer {
     fn alloc(memory_type: MemoryType, size: usize) -> Result<Self> {
-        let ptr = boot::allocate_pool(memory_type, size).unwrap();
+        let ptr = boot::allocate_pool(memory_type, size)?;
 
         Ok(FastbootBuffer {
             ptr,
@@ -245,70 +313,91 @@ fn create_empty_rt_properties_table() -> Result<FastbootBuffer> {
     Ok(buf)
 }
 
-fn handle_boot(usb_device: &ScopedProtocol<EfiUsbDevice>, payload: &[u8]) -> Result {
+fn handle_boot(transport: &dyn FastbootTransport, payload: &[u8]) -> FastbootResult {
     let (handle, _initrd) = if is_peimage(payload) {
         (handle_peimage(payload)?, None)
     } else if is_bootimg_v0(payload) {
-        let result = handle_bootimg_v0(payload);
-        if let Err(err) = result {
-            fastboot_respond(usb_device, &format!("FAILfailed: {}", err.data()))?;
-            return Ok(());
-        }
-        (result.unwrap(), None)
+        (handle_bootimg_v0(payload)?, None)
     } else if is_bootimg_v2(payload) {
-        let result = handle_bootimg_v2(payload);
-        if let Err(err) = result {
-            fastboot_respond(usb_device, &format!("FAILfailed: {}", err.data()))?;
-            return Ok(());
-        }
-        result.unwrap()
+        handle_bootimg_v2(payload)?
     } else {
-        fastboot_respond(usb_device, "FAIL")?;
-        return Err(uefi::Error::new(Status::INVALID_PARAMETER, ()));
+        return Err(FastbootError::InvalidArgument("image format"));
     };
 
     create_empty_rt_properties_table()?.install_configuration_table(&EFI_RT_PROPERTIES_TABLE)?;
 
-    fastboot_respond(usb_device, "OKAY")?;
-    boot::start_image(handle)?;
+    // The image may take over the USB controller, so the OKAY has to be
+    // on the wire before it starts.
+    transport.respond("OKAY")?;
+    transport.flush()?;
+
+    // The host already has its OKAY, so an image that fails to start or
+    // returns can only be logged.
+    if let Err(err) = boot::start_image(handle) {
+        info!("boot image returned {:?}", err.status());
+    }
 
     Ok(())
 }
 
-fn handle_getvar(usb_device: &ScopedProtocol<EfiUsbDevice>, variable: &str) -> Result {
-    let response = match variable {
-        "version" => Some("0.4"),
-        "version-bootloader" => Some(env!("BUILD_VERSION")),
-        _ => None,
-    };
+fn handle_flash(transport: &dyn FastbootTransport, name: &str, payload: &[u8]) -> FastbootResult {
+    let name = slot::resolve_partition_name(name);
+    partition::flash(&name, payload)?;
+
+    transport.respond("OKAY")
+}
 
-    let response = match response {
-        Some(response) => format!("OKAY{response}"),
-        None => format!("FAILunknown variable: {variable}"),
+fn handle_erase(transport: &dyn FastbootTransport, name: &str) -> FastbootResult {
+    let mut reported = 0;
+    let progress = |erased: u64, total: u64| {
+        let percent = erased * 100 / total;
+        if total >= ERASE_PROGRESS_THRESHOLD && percent >= reported + 10 {
+            reported = percent;
+            if transport.respond(&format!("INFOerased {percent}%")).is_ok() {
+                let _ = transport.flush();
+            }
+        }
     };
 
-    fastboot_respond(usb_device, &response).expect("Failed to send response");
+    let name = slot::resolve_partition_name(name);
+    partition::erase(&name, progress)?;
 
-    Ok(())
+    transport.respond("OKAY")
 }
 
-fn generate_serial_number() -> Result<CString16> {
-    let handle = boot::get_handle_for_protocol::<MemCardInfo>()?;
-    let memcardinfo = boot::open_protocol_exclusive::<MemCardInfo>(handle)?;
-    let cardinfo = memcardinfo.get_card_info()?;
+fn handle_set_active(transport: &dyn FastbootTransport, slot: &str) -> FastbootResult {
+    slot::set_active(slot)?;
 
-    let serial = if cardinfo.card_type[0..3] == [b'U', b'F', b'S'] {
-        let serial_number_len = cardinfo.serial_number_len as usize;
-        boot::calculate_crc32(&cardinfo.serial_number[..serial_number_len])?
-    } else {
-        u32::from_le_bytes(cardinfo.serial_number[0..4].try_into().unwrap())
-    };
+    transport.respond("OKAY")
+}
 
-    let mut buf = [0; 9];
-    let serial = format!("{serial:08x}");
-    let serial = CStr16::from_str_with_buf(&serial, &mut buf).unwrap();
+fn handle_reboot(transport: &dyn FastbootTransport, target: RebootTarget) -> FastbootResult {
+    reboot::prepare(target)?;
 
-    Ok(serial.into())
+    transport.respond("OKAY")?;
+    let _ = transport.flush();
+
+    reboot::reboot(target)
+}
+
+fn handle_getvar(
+    transport: &dyn FastbootTransport,
+    variables: &Variables,
+    variable: &str,
+) -> FastbootResult {
+    if variable == "all" {
+        for (name, value) in variables.all() {
+            transport.respond(&format!("INFO{name}:{value}"))?;
+        }
+
+        return transport.respond("OKAY");
+    }
+
+    let Some(value) = variables.get(variable) else {
+        return Err(FastbootError::UnknownVariable(variable.into()));
+    };
+
+    transport.respond(&format!("OKAY{value}"))
 }
 
 #[entry]
@@ -318,82 +407,56 @@ fn main() -> Status {
     let version = env!("BUILD_VERSION");
     info!("fastboot.efi {}", version);
 
-    let serial_number =
-        generate_serial_number().unwrap_or(CString16::try_from("deadcafe").unwrap());
+    let serial_number = serial::serial_number();
+
+    let mut variables = Variables::new(&serial_number);
+    variables.register(Box::new(BcbVariables::new()));
 
     signal_usb_controller_init().expect("failed to signal usb controller initialization");
 
-    let usb_device = fastboot_open(&serial_number).expect("unable to open USB device");
+    let usb_config = UsbConfig::new(serial_number.value.clone());
+    info!(
+        "usb identity {:04x}:{:04x} {} {}",
+        usb_config.vendor_id, usb_config.product_id, usb_config.manufacturer, usb_config.product
+    );
+    if usb_config.serial_console {
+        info!("mirroring console to usb serial interface");
+    }
 
-    let command_buffer = usb_device
-        .allocate_transfer_buffer(1024 * 1024)
-        .expect("failed to allocate command buffer");
+    let usb_protocol = fastboot_open().expect("unable to open USB device");
+    let usb_device = UsbSession::new(&usb_protocol, usb_config.descriptors())
+        .expect("failed to start usb session");
+    variables.register(Box::new(usb_device.variables()));
 
-    let mut loaded_data: Option<&[u8]> = None;
+    let transport = UsbTransport::new(&usb_device).expect("failed to allocate command buffer");
 
-    'message_loop: loop {
-        let event = usb_device.handle_event().expect("handle_event failed");
+    let mut commands = Commands::new();
+    commands.register_oem(Box::new(UmsCommand::new(&usb_device, &usb_config)));
 
-        match event {
-            usb_device::EfiUsbDeviceEvent::NoEvent => {}
-            usb_device::EfiUsbDeviceEvent::Connected => {
-                usb_device
-                    .send(usb_device::ENDPOINT_OUT, 1024 * 1024, command_buffer)
-                    .expect("failed to queue command buffer");
-            }
-            usb_device::EfiUsbDeviceEvent::OutData(data) => {
-                let request = core::str::from_utf8(data).unwrap();
-
-                if request.starts_with("download:") {
-                    let parts = request.split(':').nth(1).unwrap();
-                    let size = usize::from_str_radix(parts, 16).unwrap();
-
-                    loaded_data = Some(handle_download(&usb_device, size).unwrap());
-                } else if request == "boot" {
-                    if let Some(payload) = loaded_data {
-                        handle_boot(&usb_device, payload).expect("Failed to handle boot command");
-                    } else {
-                        fastboot_respond(&usb_device, "FAILdownload something first")
-                            .expect("Failed to send response");
-                    };
-                } else if request == "reboot" {
-                    let _ = fastboot_respond(&usb_device, "OKAY");
-
-                    let reset_data = cstr16!("RESET_PARAM");
-                    runtime::reset(
-                        ResetType::COLD,
-                        Status::SUCCESS,
-                        Some(reset_data.as_bytes()),
-                    );
-                } else if request == "continue" {
-                    let _ = fastboot_respond(&usb_device, "OKAY");
-
-                    break 'message_loop;
-                } else if request.starts_with("getvar") {
-                    let Some(variable) = request.split(':').nth(1) else {
-                        fastboot_respond(&usb_device, "FAILinvalid getvar")
-                            .expect("Failed to send response");
-                        continue;
-                    };
-
-                    handle_getvar(&usb_device, variable).expect("Failed to handle getvar command");
-                } else {
-                    fastboot_respond(&usb_device, "FAILunknown command")
-                        .expect("Failed to send response");
-                }
-
-                usb_device
-                    .send(usb_device::ENDPOINT_OUT, 1024 * 1024, command_buffer)
-                    .expect("failed to queue command buffer");
+    let mut context = Context {
+        transport: &transport,
+        allocator: &PoolAllocator,
+        variables: &variables,
+        loaded_data: None,
+        staged: None,
+    };
+
+    loop {
+        let request = match transport.receive_command() {
+            Ok(request) => request,
+            Err(err) => {
+                info!("failed to receive command: {err}");
+                continue;
             }
-            _ => info!("{:#?}", event),
         };
+
+        if commands.dispatch(&mut context, request) == Action::Exit {
+            break;
+        }
     }
 
     usb_device.stop().expect("Failed to stop USB");
-    usb_device
-        .free_transfer_buffer(command_buffer)
-        .expect("Failed to free transfer buffer");
+    transport.close();
 
     Status::SUCCESS
 }
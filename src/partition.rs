@@ -0,0 +1,269 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::info;
+use uefi::boot::{self, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::partition::{GptPartitionEntry, GptPartitionType, PartitionInfo};
+use uefi::proto::ProtocolPointer;
+use uefi::{Error, Guid, Handle, Result, Status};
+
+use crate::erase_block::EraseBlock;
+use crate::sparse::{is_sparse_image, sparse_image_size, write_sparse_image};
+use crate::UefiResultContext;
+
+const WRITE_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+pub(crate) struct Partition {
+    handle: Handle,
+    name: String,
+    entry: GptPartitionEntry,
+    block_size: u32,
+}
+
+// Enumeration only inspects the protocols, opening them exclusively would
+// disconnect e.g. the file system driver from the ESP.
+pub(crate) fn get_protocol<P: ProtocolPointer + ?Sized>(
+    handle: Handle,
+) -> Result<ScopedProtocol<P>> {
+    unsafe {
+        boot::open_protocol::<P>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+}
+
+fn partition_name(entry: &GptPartitionEntry) -> String {
+    let name = entry.partition_name;
+    let name = name.iter().map(|c| u16::from(*c)).take_while(|c| *c != 0);
+
+    char::decode_utf16(name)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+pub(crate) fn partitions() -> Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+
+    for handle in boot::find_handles::<PartitionInfo>()? {
+        let Ok(info) = get_protocol::<PartitionInfo>(handle) else {
+            continue;
+        };
+        let Some(entry) = info.gpt_partition_entry() else {
+            continue;
+        };
+        let Ok(block_io) = get_protocol::<BlockIO>(handle) else {
+            continue;
+        };
+
+        partitions.push(Partition {
+            handle,
+            name: partition_name(entry),
+            entry: *entry,
+            block_size: block_io.media().block_size(),
+        });
+    }
+
+    Ok(partitions)
+}
+
+pub(crate) fn find_partition(name: &str) -> Result<Partition, &'static str> {
+    partitions()
+        .with_context("failed to enumerate partitions")?
+        .into_iter()
+        .find(|partition| partition.name == name)
+        .ok_or(Error::new(Status::NOT_FOUND, "partition not found"))
+}
+
+impl Partition {
+    pub(crate) fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn unique_guid(&self) -> Guid {
+        self.entry.unique_partition_guid
+    }
+
+    pub(crate) fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.entry.num_blocks().unwrap_or(0) * self.block_size as u64
+    }
+
+    pub(crate) fn fs_type(&self) -> &'static str {
+        match self.entry.partition_type_guid {
+            GptPartitionType::EFI_SYSTEM_PARTITION => "vfat",
+            _ => "raw",
+        }
+    }
+
+    pub(crate) fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if offset + buf.len() as u64 > self.size() {
+            return Err(Error::new(
+                Status::BAD_BUFFER_SIZE,
+                "read past end of partition",
+            ));
+        }
+
+        let block_io =
+            get_protocol::<BlockIO>(self.handle).with_context("failed to open partition")?;
+        let block_size = self.block_size as u64;
+
+        let lba = offset / block_size;
+        let skip = (offset % block_size) as usize;
+        let len = (skip + buf.len()).next_multiple_of(block_size as usize);
+
+        let mut blocks = vec![0u8; len];
+        block_io
+            .read_blocks(block_io.media().media_id(), lba, &mut blocks)
+            .with_context("failed to read partition")?;
+
+        buf.copy_from_slice(&blocks[skip..skip + buf.len()]);
+
+        Ok(())
+    }
+
+    pub(crate) fn open(&self) -> Result<PartitionWriter, &'static str> {
+        let block_io = boot::open_protocol_exclusive::<BlockIO>(self.handle)
+            .with_context("failed to open partition")?;
+
+        if block_io.media().is_read_only() {
+            return Err(Error::new(
+                Status::WRITE_PROTECTED,
+                "partition is read-only",
+            ));
+        }
+
+        Ok(PartitionWriter {
+            media_id: block_io.media().media_id(),
+            block_size: self.block_size as usize,
+            size: self.size(),
+            block_io,
+        })
+    }
+}
+
+pub(crate) struct PartitionWriter {
+    block_io: ScopedProtocol<BlockIO>,
+    media_id: u32,
+    block_size: usize,
+    size: u64,
+}
+
+impl PartitionWriter {
+    pub(crate) fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        if !offset.is_multiple_of(self.block_size as u64) {
+            return Err(Error::new(Status::INVALID_PARAMETER, "unaligned write"));
+        }
+        if offset + data.len() as u64 > self.size {
+            return Err(Error::new(
+                Status::BAD_BUFFER_SIZE,
+                "write past end of partition",
+            ));
+        }
+
+        let mut lba = offset / self.block_size as u64;
+        let aligned_len = data.len() - data.len() % self.block_size;
+        let (aligned, tail) = data.split_at(aligned_len);
+
+        for chunk in aligned.chunks(WRITE_CHUNK_SIZE) {
+            self.block_io
+                .write_blocks(self.media_id, lba, chunk)
+                .with_context("failed to write partition")?;
+            lba += (chunk.len() / self.block_size) as u64;
+        }
+
+        if !tail.is_empty() {
+            let mut block = vec![0u8; self.block_size];
+            block[..tail.len()].copy_from_slice(tail);
+            self.block_io
+                .write_blocks(self.media_id, lba, &block)
+                .with_context("failed to write partition")?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn zero_fill(
+        &mut self,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), &'static str> {
+        let zeroes = vec![0u8; WRITE_CHUNK_SIZE.min(self.size as usize)];
+        let mut offset = 0;
+
+        while offset < self.size {
+            let len = (self.size - offset).min(zeroes.len() as u64) as usize;
+            self.write(offset, &zeroes[..len])?;
+            offset += len as u64;
+
+            progress(offset, self.size);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), &'static str> {
+        self.block_io
+            .flush_blocks()
+            .with_context("failed to flush partition")
+    }
+}
+
+pub(crate) fn flash(name: &str, payload: &[u8]) -> Result<(), &'static str> {
+    let partition = find_partition(name)?;
+
+    let sparse = is_sparse_image(payload);
+    let image_size = if sparse {
+        sparse_image_size(payload)?
+    } else {
+        payload.len() as u64
+    };
+
+    if image_size > partition.size() {
+        return Err(Error::new(
+            Status::BAD_BUFFER_SIZE,
+            "image too large for partition",
+        ));
+    }
+
+    let mut writer = partition.open()?;
+    if sparse {
+        write_sparse_image(&mut writer, payload)?;
+    } else {
+        writer.write(0, payload)?;
+    }
+    writer.flush()
+}
+
+pub(crate) fn erase(name: &str, progress: impl FnMut(u64, u64)) -> Result<(), &'static str> {
+    let partition = find_partition(name)?;
+    let mut writer = partition.open()?;
+
+    if let Ok(erase_block) = boot::open_protocol_exclusive::<EraseBlock>(partition.handle) {
+        match erase_block.erase_blocks(writer.media_id, 0, partition.size() as usize) {
+            Ok(()) => return writer.flush(),
+            Err(err) => info!("erase blocks failed ({:?}), zero-filling", err.status()),
+        }
+    }
+
+    writer.zero_fill(progress)?;
+    writer.flush()
+}
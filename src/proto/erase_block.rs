@@ -0,0 +1,31 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::ffi::c_void;
+use uefi::{guid, Guid, Status};
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct EraseBlockToken {
+    pub event: *mut c_void,
+    pub transaction_status: Status,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct EraseBlockProtocol {
+    pub revision: u64,
+    pub erase_length_granularity: u32,
+
+    pub erase_blocks: unsafe extern "efiapi" fn(
+        this: *const EraseBlockProtocol,
+        media_id: u32,
+        lba: u64,
+        token: *mut EraseBlockToken,
+        size: usize,
+    ) -> Status,
+}
+
+impl EraseBlockProtocol {
+    pub const GUID: Guid = guid!("95a9a93e-a86e-4926-aaef-9918e772d987");
+}
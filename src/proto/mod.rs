@@ -1,5 +1,6 @@
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 // SPDX-License-Identifier: BSD-3-Clause
 
+pub mod erase_block;
 pub mod memcardinfo;
 pub mod usb_device;
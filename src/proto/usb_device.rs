@@ -89,6 +89,82 @@ pub(crate) struct UsbEndpointDescriptor {
 pub(crate) const USB_ENDPOINT_DESCRIPTOR_LEN: u8 = 7;
 pub(crate) const USB_ENDPOINT_DESCRIPTOR_TYPE: u8 = 5;
 pub(crate) const USB_ENDPOINT_TYPE_BULK: u8 = 2;
+pub(crate) const USB_ENDPOINT_TYPE_INTERRUPT: u8 = 3;
+
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub(crate) struct UsbInterfaceAssociationDescriptor {
+    pub(crate) bLength: u8,
+    pub(crate) bDescriptorType: u8,
+    pub(crate) bFirstInterface: u8,
+    pub(crate) bInterfaceCount: u8,
+    pub(crate) bFunctionClass: u8,
+    pub(crate) bFunctionSubClass: u8,
+    pub(crate) bFunctionProtocol: u8,
+    pub(crate) iFunction: u8,
+}
+pub(crate) const USB_INTERFACE_ASSOCIATION_DESCRIPTOR_LEN: u8 = 8;
+pub(crate) const USB_INTERFACE_ASSOCIATION_DESCRIPTOR_TYPE: u8 = 0xb;
+
+pub(crate) const USB_CLASS_MASS_STORAGE: u8 = 0x8;
+pub(crate) const USB_MASS_STORAGE_SUBCLASS_SCSI: u8 = 0x6;
+pub(crate) const USB_MASS_STORAGE_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+pub(crate) const USB_CLASS_CDC: u8 = 0x2;
+pub(crate) const USB_CLASS_CDC_DATA: u8 = 0xa;
+pub(crate) const USB_CDC_SUBCLASS_ACM: u8 = 0x2;
+pub(crate) const USB_CDC_CS_INTERFACE_DESCRIPTOR_TYPE: u8 = 0x24;
+
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub(crate) struct UsbCdcHeaderDescriptor {
+    pub(crate) bLength: u8,
+    pub(crate) bDescriptorType: u8,
+    pub(crate) bDescriptorSubtype: u8,
+    pub(crate) bcdCDC: u16,
+}
+pub(crate) const USB_CDC_HEADER_DESCRIPTOR_LEN: u8 = 5;
+pub(crate) const USB_CDC_HEADER_DESCRIPTOR_SUBTYPE: u8 = 0x0;
+
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub(crate) struct UsbCdcCallManagementDescriptor {
+    pub(crate) bLength: u8,
+    pub(crate) bDescriptorType: u8,
+    pub(crate) bDescriptorSubtype: u8,
+    pub(crate) bmCapabilities: u8,
+    pub(crate) bDataInterface: u8,
+}
+pub(crate) const USB_CDC_CALL_MANAGEMENT_DESCRIPTOR_LEN: u8 = 5;
+pub(crate) const USB_CDC_CALL_MANAGEMENT_DESCRIPTOR_SUBTYPE: u8 = 0x1;
+
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub(crate) struct UsbCdcAcmDescriptor {
+    pub(crate) bLength: u8,
+    pub(crate) bDescriptorType: u8,
+    pub(crate) bDescriptorSubtype: u8,
+    pub(crate) bmCapabilities: u8,
+}
+pub(crate) const USB_CDC_ACM_DESCRIPTOR_LEN: u8 = 4;
+pub(crate) const USB_CDC_ACM_DESCRIPTOR_SUBTYPE: u8 = 0x2;
+
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub(crate) struct UsbCdcUnionDescriptor {
+    pub(crate) bLength: u8,
+    pub(crate) bDescriptorType: u8,
+    pub(crate) bDescriptorSubtype: u8,
+    pub(crate) bControlInterface: u8,
+    pub(crate) bSubordinateInterface0: u8,
+}
+pub(crate) const USB_CDC_UNION_DESCRIPTOR_LEN: u8 = 5;
+pub(crate) const USB_CDC_UNION_DESCRIPTOR_SUBTYPE: u8 = 0x6;
 
 #[allow(non_snake_case)]
 #[derive(Clone, Copy, Debug)]
@@ -224,6 +300,27 @@ pub(crate) struct ConfigDescriptorTree {
     pub(crate) endpoint1_descriptor: UsbEndpointDescriptor,
 }
 
+// The firmware only follows wTotalLength of the configuration descriptor, so
+// the composite trees are handed to it in place of a ConfigDescriptorTree.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub(crate) struct CompositeConfigDescriptorTree {
+    pub(crate) config_descriptor: UsbConfigDescriptor,
+    pub(crate) interface_descriptor: UsbInterfaceDescriptor,
+    pub(crate) endpoint0_descriptor: UsbEndpointDescriptor,
+    pub(crate) endpoint1_descriptor: UsbEndpointDescriptor,
+    pub(crate) serial_association_descriptor: UsbInterfaceAssociationDescriptor,
+    pub(crate) serial_control_interface_descriptor: UsbInterfaceDescriptor,
+    pub(crate) serial_header_descriptor: UsbCdcHeaderDescriptor,
+    pub(crate) serial_call_management_descriptor: UsbCdcCallManagementDescriptor,
+    pub(crate) serial_acm_descriptor: UsbCdcAcmDescriptor,
+    pub(crate) serial_union_descriptor: UsbCdcUnionDescriptor,
+    pub(crate) serial_notify_endpoint_descriptor: UsbEndpointDescriptor,
+    pub(crate) serial_data_interface_descriptor: UsbInterfaceDescriptor,
+    pub(crate) serial_in_endpoint_descriptor: UsbEndpointDescriptor,
+    pub(crate) serial_out_endpoint_descriptor: UsbEndpointDescriptor,
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub(crate) struct BinaryObjectStore {
@@ -244,6 +341,30 @@ pub(crate) struct SuperSpeedConfigDescriptorTree {
     pub(crate) superspeed_ednpoint1_compaion_descriptor: UsbSuperSpeedCompanionDescriptor,
 }
 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub(crate) struct SuperSpeedCompositeConfigDescriptorTree {
+    pub(crate) config_descriptor: UsbConfigDescriptor,
+    pub(crate) interface_descriptor: UsbInterfaceDescriptor,
+    pub(crate) endpoint0_descriptor: UsbEndpointDescriptor,
+    pub(crate) superspeed_endpoint0_companion_descriptor: UsbSuperSpeedCompanionDescriptor,
+    pub(crate) endpoint1_descriptor: UsbEndpointDescriptor,
+    pub(crate) superspeed_endpoint1_companion_descriptor: UsbSuperSpeedCompanionDescriptor,
+    pub(crate) serial_association_descriptor: UsbInterfaceAssociationDescriptor,
+    pub(crate) serial_control_interface_descriptor: UsbInterfaceDescriptor,
+    pub(crate) serial_header_descriptor: UsbCdcHeaderDescriptor,
+    pub(crate) serial_call_management_descriptor: UsbCdcCallManagementDescriptor,
+    pub(crate) serial_acm_descriptor: UsbCdcAcmDescriptor,
+    pub(crate) serial_union_descriptor: UsbCdcUnionDescriptor,
+    pub(crate) serial_notify_endpoint_descriptor: UsbEndpointDescriptor,
+    pub(crate) serial_notify_companion_descriptor: UsbSuperSpeedCompanionDescriptor,
+    pub(crate) serial_data_interface_descriptor: UsbInterfaceDescriptor,
+    pub(crate) serial_in_endpoint_descriptor: UsbEndpointDescriptor,
+    pub(crate) serial_in_companion_descriptor: UsbSuperSpeedCompanionDescriptor,
+    pub(crate) serial_out_endpoint_descriptor: UsbEndpointDescriptor,
+    pub(crate) serial_out_companion_descriptor: UsbSuperSpeedCompanionDescriptor,
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub(crate) struct UsbDeviceDescriptorSet {
@@ -257,6 +378,13 @@ pub(crate) struct UsbDeviceDescriptorSet {
     pub(crate) string_descriptors: *const *const u8,
 }
 
+// Protocol header revisions. Each appended entry points to the end of the
+// table, and firmware reporting an older revision doesn't have those slots.
+/// Adds `abort_xfer` and `set_endpoint_stall_state`.
+pub(crate) const EFI_USB_DEVICE_PROTOCOL_REVISION_1_1: u64 = 0x0001_0001;
+/// Adds `start_ex`, taking SuperSpeed descriptors.
+pub(crate) const EFI_USB_DEVICE_PROTOCOL_REVISION_1_2: u64 = 0x0001_0002;
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub(crate) struct EfiUsbDeviceProtocol {
@@ -280,7 +408,7 @@ pub(crate) struct EfiUsbDeviceProtocol {
         unsafe extern "efiapi" fn(size: u64, ptr: *mut *mut u8) -> Status,
     pub(crate) free_transfer_buffer: unsafe extern "efiapi" fn(ptr: *mut u8) -> Status,
     pub(crate) stop: unsafe extern "efiapi" fn() -> Status,
-    pub(crate) abort_xfer: unsafe extern "efiapi" fn() -> Status,
+    pub(crate) abort_xfer: unsafe extern "efiapi" fn(ep_idx: u8) -> Status,
     pub(crate) set_endpoint_stall_state:
         unsafe extern "efiapi" fn(ep_idx: u8, state: bool) -> Status,
     pub(crate) start_ex: unsafe extern "efiapi" fn(desc: *const UsbDeviceDescriptorSet) -> Status,
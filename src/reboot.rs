@@ -0,0 +1,118 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::mem::size_of;
+use uefi::runtime::{self, ResetType, VariableAttributes, VariableVendor};
+use uefi::{cstr16, CStr16, Error, Result, Status};
+
+use crate::bcb;
+use crate::UefiResultContext;
+
+const EFI_OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 0x1;
+
+// Reboot reasons understood by Qualcomm firmware, passed along with the
+// "RESET_PARAM" reset data.
+const NORMAL_MODE: u8 = 0x0;
+const RECOVERY_MODE: u8 = 0x1;
+const FASTBOOT_MODE: u8 = 0x2;
+const EMERGENCY_DLOAD: u8 = 0xff;
+
+#[repr(C, packed)]
+struct ResetData {
+    data_buffer: [u16; 12],
+    bdata: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RebootTarget {
+    Normal,
+    Bootloader,
+    Recovery,
+    Fastboot,
+    Edl,
+    FirmwareUi,
+    PowerOff,
+}
+
+impl RebootTarget {
+    fn reset_type(self) -> ResetType {
+        match self {
+            Self::Normal | Self::FirmwareUi => ResetType::COLD,
+            Self::PowerOff => ResetType::SHUTDOWN,
+            _ => ResetType::PLATFORM_SPECIFIC,
+        }
+    }
+
+    fn reboot_reason(self) -> u8 {
+        match self {
+            Self::Bootloader => FASTBOOT_MODE,
+            Self::Recovery | Self::Fastboot => RECOVERY_MODE,
+            Self::Edl => EMERGENCY_DLOAD,
+            _ => NORMAL_MODE,
+        }
+    }
+}
+
+fn get_global_u64(name: &CStr16) -> u64 {
+    let mut buf = [0u8; size_of::<u64>()];
+
+    match runtime::get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut buf) {
+        Ok((value, _)) => value.try_into().map(u64::from_le_bytes).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+fn request_boot_to_firmware_ui() -> Result<(), &'static str> {
+    let supported = get_global_u64(cstr16!("OsIndicationsSupported"));
+    if supported & EFI_OS_INDICATIONS_BOOT_TO_FW_UI == 0 {
+        return Err(Error::new(
+            Status::UNSUPPORTED,
+            "boot to firmware UI not supported",
+        ));
+    }
+
+    let indications = get_global_u64(cstr16!("OsIndications"));
+
+    runtime::set_variable(
+        cstr16!("OsIndications"),
+        &VariableVendor::GLOBAL_VARIABLE,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &(indications | EFI_OS_INDICATIONS_BOOT_TO_FW_UI).to_le_bytes(),
+    )
+    .with_context("failed to set OsIndications")
+}
+
+fn write_bcb_command(command: &str) -> Result<(), &'static str> {
+    match bcb::write_command(command) {
+        Err(err) if err.status() == Status::NOT_FOUND => Ok(()),
+        result => result,
+    }
+}
+
+pub(crate) fn prepare(target: RebootTarget) -> Result<(), &'static str> {
+    match target {
+        RebootTarget::Recovery => write_bcb_command(bcb::BCB_BOOT_RECOVERY),
+        RebootTarget::Fastboot => write_bcb_command(bcb::BCB_BOOT_FASTBOOT),
+        RebootTarget::FirmwareUi => request_boot_to_firmware_ui(),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn reboot(target: RebootTarget) -> ! {
+    let mut reset_data = ResetData {
+        data_buffer: [0; 12],
+        bdata: target.reboot_reason(),
+    };
+    let reset_param = cstr16!("RESET_PARAM").to_u16_slice_with_nul();
+    let mut data_buffer = reset_data.data_buffer;
+    data_buffer[..reset_param.len()].copy_from_slice(reset_param);
+    reset_data.data_buffer = data_buffer;
+
+    let reset_data = unsafe {
+        core::slice::from_raw_parts((&raw const reset_data).cast::<u8>(), size_of::<ResetData>())
+    };
+
+    runtime::reset(target.reset_type(), Status::SUCCESS, Some(reset_data))
+}
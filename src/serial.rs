@@ -0,0 +1,305 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::slice;
+use core::time::Duration;
+use log::info;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::nvme::pass_thru::NvmePassThru;
+use uefi::proto::nvme::{NvmeQueueType, NvmeRequestBuilder};
+use uefi::runtime::{self, VariableVendor};
+use uefi::table::cfg::{SMBIOS3_GUID, SMBIOS_GUID};
+use uefi::{boot, cstr16, guid, system, Guid};
+
+use crate::memcardinfo::MemCardInfo;
+use crate::partition::get_protocol;
+
+const DEFAULT_SERIAL_NUMBER: &str = "deadcafe";
+const MAX_SERIAL_NUMBER_LEN: usize = 32;
+
+const DEFAULT_SOURCES: &str = "variable,memcardinfo,smbios,nvme";
+
+const FASTBOOT_VARIABLE_GUID: Guid = guid!("5b4c4ecc-5a3d-4b3a-9e7e-6a2d0e1f8c11");
+
+const SMBIOS_TYPE_SYSTEM: u8 = 1;
+const SMBIOS_TYPE_BASEBOARD: u8 = 2;
+const SMBIOS_TYPE_END: u8 = 127;
+const SMBIOS_SERIAL_NUMBER_OFFSET: usize = 7;
+
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+const NVME_IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+const NVME_IDENTIFY_SIZE: usize = 4096;
+const NVME_SERIAL_NUMBER: core::ops::Range<usize> = 4..24;
+
+const PLACEHOLDERS: [&str; 9] = [
+    "default string",
+    "to be filled by o.e.m.",
+    "system serial number",
+    "serial number",
+    "not specified",
+    "not applicable",
+    "n/a",
+    "none",
+    "0123456789",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SerialSource {
+    Variable,
+    MemCardInfo,
+    Smbios,
+    Nvme,
+    BlockIo,
+    Default,
+}
+
+impl SerialSource {
+    const ALL: [Self; 5] = [
+        Self::Variable,
+        Self::MemCardInfo,
+        Self::Smbios,
+        Self::Nvme,
+        Self::BlockIo,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Variable => "variable",
+            Self::MemCardInfo => "memcardinfo",
+            Self::Smbios => "smbios",
+            Self::Nvme => "nvme",
+            Self::BlockIo => "block-io",
+            Self::Default => "default",
+        }
+    }
+
+    fn read(self) -> Option<String> {
+        match self {
+            Self::Variable => variable_serial(),
+            Self::MemCardInfo => memcardinfo_serial(),
+            Self::Smbios => smbios_serial(),
+            Self::Nvme => nvme_serial(),
+            Self::BlockIo => block_io_serial(),
+            Self::Default => Some(DEFAULT_SERIAL_NUMBER.into()),
+        }
+    }
+}
+
+pub(crate) struct SerialNumber {
+    pub(crate) value: String,
+    pub(crate) source: SerialSource,
+}
+
+// Firmware often fills in placeholder serials, which are as good as none
+// when they are the same on every board.
+fn sanitize(serial: &str) -> Option<String> {
+    let serial = serial.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+
+    if PLACEHOLDERS.contains(&serial.to_ascii_lowercase().as_str()) {
+        return None;
+    }
+
+    let mut chars = serial.chars();
+    let first = chars.next()?;
+    if chars.all(|c| c == first) {
+        return None;
+    }
+
+    let serial: String = serial
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(MAX_SERIAL_NUMBER_LEN)
+        .collect();
+
+    (!serial.is_empty()).then_some(serial)
+}
+
+// Lets a board be given a serial by hand, e.g. by writing the
+// FastbootSerialNumber variable from the OS. ASCII and UCS-2 are accepted.
+fn variable_serial() -> Option<String> {
+    let (value, _) = runtime::get_variable_boxed(
+        cstr16!("FastbootSerialNumber"),
+        &VariableVendor(FASTBOOT_VARIABLE_GUID),
+    )
+    .ok()?;
+
+    let ucs2 = value.len() % 2 == 0 && value.iter().skip(1).step_by(2).all(|b| *b == 0);
+    if ucs2 {
+        let units: Vec<u16> = value
+            .as_chunks::<2>()
+            .0
+            .iter()
+            .map(|unit| u16::from_le_bytes(*unit))
+            .collect();
+        sanitize(&String::from_utf16_lossy(&units))
+    } else {
+        sanitize(&String::from_utf8_lossy(&value))
+    }
+}
+
+fn memcardinfo_serial() -> Option<String> {
+    let handle = boot::get_handle_for_protocol::<MemCardInfo>().ok()?;
+    let memcardinfo = boot::open_protocol_exclusive::<MemCardInfo>(handle).ok()?;
+    let cardinfo = memcardinfo.get_card_info().ok()?;
+
+    let serial = if cardinfo.card_type[0..3] == *b"UFS" {
+        let serial_number_len = cardinfo.serial_number_len as usize;
+        boot::calculate_crc32(&cardinfo.serial_number[..serial_number_len]).ok()?
+    } else {
+        u32::from_le_bytes(cardinfo.serial_number[0..4].try_into().unwrap())
+    };
+
+    Some(format!("{serial:08x}"))
+}
+
+fn smbios_table() -> Option<&'static [u8]> {
+    let (guid, entry_point) = system::with_config_table(|tables| {
+        [SMBIOS3_GUID, SMBIOS_GUID].into_iter().find_map(|guid| {
+            let entry = tables.iter().find(|entry| entry.guid == guid)?;
+            Some((guid, entry.address.cast::<u8>()))
+        })
+    })?;
+
+    let (address, len) = unsafe {
+        if guid == SMBIOS3_GUID {
+            let entry = slice::from_raw_parts(entry_point, 0x18);
+            if &entry[..5] != b"_SM3_" {
+                return None;
+            }
+
+            let len = u32::from_le_bytes(entry[0x0c..0x10].try_into().unwrap());
+            let address = u64::from_le_bytes(entry[0x10..0x18].try_into().unwrap());
+            (address as usize, len as usize)
+        } else {
+            let entry = slice::from_raw_parts(entry_point, 0x1f);
+            if &entry[..4] != b"_SM_" {
+                return None;
+            }
+
+            let len = u16::from_le_bytes(entry[0x16..0x18].try_into().unwrap());
+            let address = u32::from_le_bytes(entry[0x18..0x1c].try_into().unwrap());
+            (address as usize, len as usize)
+        }
+    };
+
+    if address == 0 || len == 0 {
+        return None;
+    }
+
+    Some(unsafe { slice::from_raw_parts(address as *const u8, len) })
+}
+
+// Walks the structure table, returning the serial number string of the
+// first structure of the given type.
+fn smbios_serial_of(table: &[u8], structure_type: u8) -> Option<String> {
+    let mut offset = 0;
+
+    while offset + 4 <= table.len() {
+        let ty = table[offset];
+        let len = table[offset + 1] as usize;
+        if ty == SMBIOS_TYPE_END || len < 4 || offset + len > table.len() {
+            return None;
+        }
+
+        let strings_start = offset + len;
+        let strings_len = table[strings_start..]
+            .windows(2)
+            .position(|pair| pair == [0, 0])?;
+        let strings = &table[strings_start..strings_start + strings_len];
+
+        if ty == structure_type && len > SMBIOS_SERIAL_NUMBER_OFFSET {
+            let index = table[offset + SMBIOS_SERIAL_NUMBER_OFFSET] as usize;
+            let serial = strings.split(|b| *b == 0).nth(index.checked_sub(1)?)?;
+            return sanitize(&String::from_utf8_lossy(serial));
+        }
+
+        offset = strings_start + strings_len + 2;
+    }
+
+    None
+}
+
+fn smbios_serial() -> Option<String> {
+    let table = smbios_table()?;
+
+    smbios_serial_of(table, SMBIOS_TYPE_SYSTEM)
+        .or_else(|| smbios_serial_of(table, SMBIOS_TYPE_BASEBOARD))
+}
+
+fn nvme_serial() -> Option<String> {
+    let handles = boot::find_handles::<NvmePassThru>().ok()?;
+
+    handles.into_iter().find_map(|handle| {
+        let nvme = get_protocol::<NvmePassThru>(handle).ok()?;
+
+        let request =
+            NvmeRequestBuilder::new(nvme.io_align(), NVME_ADMIN_IDENTIFY, NvmeQueueType::ADMIN)
+                .with_timeout(Duration::from_secs(1))
+                .with_cdw10(NVME_IDENTIFY_CNS_CONTROLLER)
+                .with_transfer_buffer(NVME_IDENTIFY_SIZE)
+                .ok()?
+                .build();
+
+        let response = nvme.controller().execute_command(request).ok()?;
+        let identify = response.transfer_buffer()?;
+
+        sanitize(&String::from_utf8_lossy(identify.get(NVME_SERIAL_NUMBER)?))
+    })
+}
+
+// Only derived from the media ID, which is frequently zero, and the geometry
+// of the device, so boards of the same model with the same storage get the
+// same serial number. It's a last resort that has to be asked for through
+// FASTBOOT_SERIAL_SOURCES.
+fn block_io_serial() -> Option<String> {
+    let handles = boot::find_handles::<BlockIO>().ok()?;
+
+    handles.into_iter().find_map(|handle| {
+        let block_io = get_protocol::<BlockIO>(handle).ok()?;
+        let media = block_io.media();
+        if media.is_logical_partition() || !media.is_media_present() {
+            return None;
+        }
+
+        let mut id = Vec::new();
+        id.extend_from_slice(&media.media_id().to_le_bytes());
+        id.extend_from_slice(&media.block_size().to_le_bytes());
+        id.extend_from_slice(&media.last_block().to_le_bytes());
+
+        Some(format!("{:08x}", boot::calculate_crc32(&id).ok()?))
+    })
+}
+
+fn sources() -> Vec<SerialSource> {
+    option_env!("FASTBOOT_SERIAL_SOURCES")
+        .unwrap_or(DEFAULT_SOURCES)
+        .split(',')
+        .filter_map(|name| {
+            let source = SerialSource::ALL
+                .into_iter()
+                .find(|source| source.name() == name.trim());
+            if source.is_none() {
+                info!("unknown serial number source: {name}");
+            }
+
+            source
+        })
+        .collect()
+}
+
+/// Picks the serial number from the first source that provides one, in the
+/// order given by FASTBOOT_SERIAL_SOURCES at build time.
+pub(crate) fn serial_number() -> SerialNumber {
+    let source = sources()
+        .into_iter()
+        .chain([SerialSource::Default])
+        .find_map(|source| Some((source, source.read()?)));
+
+    let (source, value) = source.unwrap_or((SerialSource::Default, DEFAULT_SERIAL_NUMBER.into()));
+    info!("serial number {value} from {}", source.name());
+
+    SerialNumber { value, source }
+}
@@ -0,0 +1,216 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use uefi::proto::media::partition::GptPartitionAttributes;
+use uefi::{Error, Result, Status};
+
+use crate::getvar::VariableProvider;
+use crate::gpt::Gpt;
+use crate::partition::{self, Partition};
+
+// A/B state as kept by Qualcomm bootloaders in the attribute bits of the
+// boot_a and boot_b partition entries.
+const PART_ATT_PRIORITY_BIT: u32 = 48;
+const PART_ATT_ACTIVE_BIT: u32 = 50;
+const PART_ATT_MAX_RETRY_CNT_BIT: u32 = 51;
+const PART_ATT_SUCCESS_BIT: u32 = 54;
+const PART_ATT_UNBOOTABLE_BIT: u32 = 55;
+
+const MAX_PRIORITY: u64 = 3;
+const MAX_RETRY_COUNT: u64 = 7;
+
+const PART_ATT_PRIORITY_VAL: u64 = MAX_PRIORITY << PART_ATT_PRIORITY_BIT;
+const PART_ATT_ACTIVE_VAL: u64 = 0x1 << PART_ATT_ACTIVE_BIT;
+const PART_ATT_MAX_RETRY_COUNT_VAL: u64 = MAX_RETRY_COUNT << PART_ATT_MAX_RETRY_CNT_BIT;
+const PART_ATT_SUCCESSFUL_VAL: u64 = 0x1 << PART_ATT_SUCCESS_BIT;
+const PART_ATT_UNBOOTABLE_VAL: u64 = 0x1 << PART_ATT_UNBOOTABLE_BIT;
+
+const SLOT_PARTITION: &str = "boot";
+const SLOT_SUFFIXES: [&str; 2] = ["a", "b"];
+
+struct SlotAttributes(u64);
+
+impl SlotAttributes {
+    fn priority(&self) -> u64 {
+        (self.0 & PART_ATT_PRIORITY_VAL) >> PART_ATT_PRIORITY_BIT
+    }
+
+    fn active(&self) -> bool {
+        self.0 & PART_ATT_ACTIVE_VAL != 0
+    }
+
+    fn retry_count(&self) -> u64 {
+        (self.0 & PART_ATT_MAX_RETRY_COUNT_VAL) >> PART_ATT_MAX_RETRY_CNT_BIT
+    }
+
+    fn successful(&self) -> bool {
+        self.0 & PART_ATT_SUCCESSFUL_VAL != 0
+    }
+
+    fn unbootable(&self) -> bool {
+        self.0 & PART_ATT_UNBOOTABLE_VAL != 0
+    }
+}
+
+fn parse_slot(slot: &str) -> Option<&'static str> {
+    let slot = slot.strip_prefix('_').unwrap_or(slot);
+    SLOT_SUFFIXES.into_iter().find(|suffix| *suffix == slot)
+}
+
+fn slot_partition(slot: &str) -> Result<Partition, &'static str> {
+    partition::find_partition(&format!("{SLOT_PARTITION}_{slot}"))
+}
+
+fn slot_attributes(slot: &str) -> Option<SlotAttributes> {
+    let partition = slot_partition(slot).ok()?;
+    let (gpt, index) = Gpt::find_disk(partition.unique_guid()).ok()?;
+
+    Some(SlotAttributes(gpt.attributes(index).bits()))
+}
+
+fn slot_count() -> usize {
+    SLOT_SUFFIXES
+        .iter()
+        .filter(|slot| slot_partition(slot).is_ok())
+        .count()
+}
+
+pub(crate) fn current_slot() -> Option<&'static str> {
+    let slots: Vec<_> = SLOT_SUFFIXES
+        .into_iter()
+        .filter_map(|slot| Some((slot, slot_attributes(slot)?)))
+        .collect();
+
+    slots
+        .iter()
+        .find(|(_, attributes)| attributes.active())
+        .or_else(|| {
+            slots
+                .iter()
+                .max_by_key(|(_, attributes)| attributes.priority())
+        })
+        .map(|(slot, _)| *slot)
+}
+
+pub(crate) fn set_active(slot: &str) -> Result<(), &'static str> {
+    let Some(slot) = parse_slot(slot) else {
+        return Err(Error::new(Status::INVALID_PARAMETER, "invalid slot"));
+    };
+
+    // Every slot's partition is looked up before any attributes are written,
+    // so that a missing partner can't leave the slots half switched.
+    let mut partitions = Vec::new();
+    for other in SLOT_SUFFIXES {
+        let partition = slot_partition(other)?;
+        Gpt::find_disk(partition.unique_guid())?;
+        partitions.push((other, partition));
+    }
+
+    // The table is read again for each write, as both partitions are
+    // usually on the same disk.
+    for (other, partition) in partitions {
+        let (mut gpt, index) = Gpt::find_disk(partition.unique_guid())?;
+        let mut attributes = gpt.attributes(index).bits();
+
+        if other == slot {
+            attributes |= PART_ATT_PRIORITY_VAL | PART_ATT_ACTIVE_VAL;
+            attributes |= PART_ATT_MAX_RETRY_COUNT_VAL;
+            attributes &= !(PART_ATT_SUCCESSFUL_VAL | PART_ATT_UNBOOTABLE_VAL);
+        } else {
+            attributes &= !(PART_ATT_PRIORITY_VAL | PART_ATT_ACTIVE_VAL);
+            attributes |= (MAX_PRIORITY - 1) << PART_ATT_PRIORITY_BIT;
+        }
+
+        gpt.set_attributes(index, GptPartitionAttributes::from_bits_retain(attributes));
+        gpt.write()?;
+    }
+
+    Ok(())
+}
+
+// Resolves a bare partition name, e.g. "boot", to the partition of the
+// currently active slot, unless a partition of that exact name exists.
+pub(crate) fn resolve_partition_name(name: &str) -> String {
+    if partition::find_partition(name).is_ok() {
+        return name.into();
+    }
+
+    match current_slot() {
+        Some(slot) => format!("{name}_{slot}"),
+        None => name.into(),
+    }
+}
+
+pub(crate) struct SlotVariables;
+
+impl SlotVariables {
+    fn slot_variable(variable: &str, slot: &str) -> Option<String> {
+        let attributes = slot_attributes(parse_slot(slot)?)?;
+        let yes_no = |value: bool| if value { "yes" } else { "no" }.to_string();
+
+        match variable {
+            "slot-successful" => Some(yes_no(attributes.successful())),
+            "slot-unbootable" => Some(yes_no(attributes.unbootable())),
+            "slot-retry-count" => Some(attributes.retry_count().to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl VariableProvider for SlotVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        match name.split_once(':') {
+            None => match name {
+                "current-slot" => current_slot().map(Into::into),
+                "slot-count" => Some(slot_count().to_string()),
+                _ => None,
+            },
+            Some(("has-slot", partition)) => {
+                let partitions = partition::partitions().ok()?;
+                let has_slot = partitions
+                    .iter()
+                    .any(|p| p.name() == format!("{partition}_{}", SLOT_SUFFIXES[0]));
+                let exists = has_slot || partitions.iter().any(|p| p.name() == partition);
+
+                exists.then(|| if has_slot { "yes" } else { "no" }.into())
+            }
+            Some((variable, slot)) => Self::slot_variable(variable, slot),
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        let mut variables = Vec::new();
+
+        let slot_count = slot_count();
+        variables.push(("slot-count".to_string(), slot_count.to_string()));
+        if slot_count == 0 {
+            return variables;
+        }
+
+        if let Some(slot) = current_slot() {
+            variables.push(("current-slot".to_string(), slot.to_string()));
+        }
+
+        for slot in SLOT_SUFFIXES {
+            for variable in ["slot-successful", "slot-unbootable", "slot-retry-count"] {
+                if let Some(value) = Self::slot_variable(variable, slot) {
+                    variables.push((format!("{variable}:{slot}"), value));
+                }
+            }
+        }
+
+        let suffix = format!("_{}", SLOT_SUFFIXES[0]);
+        if let Ok(partitions) = partition::partitions() {
+            for partition in partitions {
+                if let Some(base) = partition.name().strip_suffix(&suffix) {
+                    variables.push((format!("has-slot:{base}"), "yes".to_string()));
+                }
+            }
+        }
+
+        variables
+    }
+}
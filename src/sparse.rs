@@ -0,0 +1,440 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use uefi::boot;
+use uefi::{Error, Result, Status};
+
+use crate::partition::PartitionWriter;
+use crate::UefiResultContext;
+
+const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+const FILL_BUFFER_SIZE: usize = 1024 * 1024;
+
+const CRC32_POLY: u32 = 0xedb88320;
+
+#[repr(C, packed)]
+struct SparseHeader {
+    magic: u32,
+    major_version: u16,
+    minor_version: u16,
+    file_hdr_sz: u16,
+    chunk_hdr_sz: u16,
+    blk_sz: u32,
+    total_blks: u32,
+    total_chunks: u32,
+    image_checksum: u32,
+}
+
+#[repr(C, packed)]
+struct ChunkHeader {
+    chunk_type: u16,
+    reserved: u16,
+    chunk_sz: u32,
+    total_sz: u32,
+}
+
+pub(crate) fn is_sparse_image(payload: &[u8]) -> bool {
+    if payload.len() < size_of::<SparseHeader>() {
+        return false;
+    }
+
+    let header: &SparseHeader = unsafe { &*(payload.as_ptr().cast()) };
+    header.magic == SPARSE_HEADER_MAGIC
+}
+
+fn sparse_header(payload: &[u8]) -> Result<&SparseHeader, &'static str> {
+    let header: &SparseHeader = unsafe { &*(payload.as_ptr().cast()) };
+
+    if header.major_version != 1 {
+        return Err(Error::new(
+            Status::UNSUPPORTED,
+            "unsupported sparse image version",
+        ));
+    }
+
+    if (header.file_hdr_sz as usize) < size_of::<SparseHeader>()
+        || (header.chunk_hdr_sz as usize) < size_of::<ChunkHeader>()
+        || header.blk_sz == 0
+        || !header.blk_sz.is_multiple_of(4)
+    {
+        return Err(Error::new(
+            Status::INVALID_PARAMETER,
+            "malformed sparse image header",
+        ));
+    }
+
+    Ok(header)
+}
+
+pub(crate) fn sparse_image_size(payload: &[u8]) -> Result<u64, &'static str> {
+    let header = sparse_header(payload)?;
+
+    Ok(header.total_blks as u64 * header.blk_sz as u64)
+}
+
+// The CRC32 chunks carry the checksum of all data expanded so far, while the
+// firmware only computes checksums of individual buffers. Stitch those
+// together by combining CRCs, as done by zlib's crc32_combine().
+fn crc32_multmodp(a: u32, mut b: u32) -> u32 {
+    let mut m = 1u32 << 31;
+    let mut p = 0;
+
+    loop {
+        if a & m != 0 {
+            p ^= b;
+            if a & (m - 1) == 0 {
+                break;
+            }
+        }
+        m >>= 1;
+        b = if b & 1 != 0 {
+            (b >> 1) ^ CRC32_POLY
+        } else {
+            b >> 1
+        };
+    }
+
+    p
+}
+
+fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    // x^(8 * len2) mod p(x), by repeated squaring of x^8
+    let mut p = 1u32 << 31;
+    let mut q = 1u32 << 23;
+    let mut n = len2;
+
+    while n != 0 {
+        if n & 1 != 0 {
+            p = crc32_multmodp(q, p);
+        }
+        q = crc32_multmodp(q, q);
+        n >>= 1;
+    }
+
+    crc32_multmodp(p, crc1) ^ crc2
+}
+
+fn crc32_repeat(crc: u32, len: u64, mut count: u64) -> u32 {
+    let mut result = 0;
+    let mut base = crc;
+    let mut base_len = len;
+
+    while count != 0 {
+        if count & 1 != 0 {
+            result = crc32_combine(result, base, base_len);
+        }
+        base = crc32_combine(base, base, base_len);
+        base_len *= 2;
+        count >>= 1;
+    }
+
+    result
+}
+
+enum ChunkData<'a> {
+    Raw(&'a [u8]),
+    Fill([u8; 4]),
+    DontCare,
+    Crc32(u32),
+}
+
+struct Chunk<'a> {
+    offset: u64,
+    len: u64,
+    data: ChunkData<'a>,
+}
+
+/// Walks the chunks of a sparse image, checking each against the bounds of
+/// the payload. Iteration ends at the first malformed chunk.
+struct Chunks<'a> {
+    payload: &'a [u8],
+    blk_sz: u64,
+    chunk_hdr_sz: usize,
+    pos: usize,
+    offset: u64,
+    remaining: u32,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(payload: &'a [u8], header: &SparseHeader) -> Self {
+        Self {
+            payload,
+            blk_sz: header.blk_sz as u64,
+            chunk_hdr_sz: header.chunk_hdr_sz as usize,
+            pos: header.file_hdr_sz as usize,
+            offset: 0,
+            remaining: header.total_chunks,
+        }
+    }
+
+    fn parse(&mut self) -> Result<Chunk<'a>, &'static str> {
+        let payload = self.payload;
+        let pos = self.pos;
+        if pos + self.chunk_hdr_sz > payload.len() {
+            return Err(Error::new(Status::END_OF_FILE, "truncated sparse image"));
+        }
+
+        let chunk: &ChunkHeader = unsafe { &*(payload[pos..].as_ptr().cast()) };
+        let total_sz = chunk.total_sz as usize;
+        let len = chunk.chunk_sz as u64 * self.blk_sz;
+
+        if total_sz < self.chunk_hdr_sz || pos + total_sz > payload.len() {
+            return Err(Error::new(Status::END_OF_FILE, "truncated sparse image"));
+        }
+
+        let body = &payload[pos + self.chunk_hdr_sz..pos + total_sz];
+        let word = |what| {
+            body.get(..4)
+                .map(|word| word.try_into().unwrap())
+                .ok_or(Error::new(Status::INVALID_PARAMETER, what))
+        };
+
+        let data = match chunk.chunk_type {
+            CHUNK_TYPE_RAW if body.len() as u64 != len => {
+                return Err(Error::new(Status::INVALID_PARAMETER, "malformed raw chunk"));
+            }
+            CHUNK_TYPE_RAW => ChunkData::Raw(body),
+            CHUNK_TYPE_FILL => ChunkData::Fill(word("malformed fill chunk")?),
+            CHUNK_TYPE_DONT_CARE => ChunkData::DontCare,
+            CHUNK_TYPE_CRC32 => {
+                ChunkData::Crc32(u32::from_le_bytes(word("malformed crc32 chunk")?))
+            }
+            _ => {
+                return Err(Error::new(Status::UNSUPPORTED, "unknown sparse chunk type"));
+            }
+        };
+
+        let chunk = Chunk {
+            offset: self.offset,
+            len,
+            data,
+        };
+        self.pos += total_sz;
+        self.offset += len;
+
+        Ok(chunk)
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Chunk<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let chunk = self.parse();
+        self.remaining = match chunk {
+            Ok(_) => self.remaining - 1,
+            Err(_) => 0,
+        };
+
+        Some(chunk)
+    }
+}
+
+pub(crate) fn write_sparse_image(
+    writer: &mut PartitionWriter,
+    payload: &[u8],
+) -> Result<(), &'static str> {
+    let header = sparse_header(payload)?;
+
+    // Every chunk starts on a sparse block, so those have to line up with
+    // the partition's blocks for any of the writes to succeed.
+    if !(header.blk_sz as usize).is_multiple_of(writer.block_size()) {
+        return Err(Error::new(
+            Status::INVALID_PARAMETER,
+            "sparse block size not a multiple of the partition block size",
+        ));
+    }
+
+    let mut crc: u32 = 0;
+
+    for chunk in Chunks::new(payload, header) {
+        let Chunk { offset, len, data } = chunk?;
+
+        match data {
+            ChunkData::Raw(data) => {
+                writer.write(offset, data)?;
+
+                let data_crc = boot::calculate_crc32(data).with_context("failed to compute crc")?;
+                crc = crc32_combine(crc, data_crc, len);
+            }
+            ChunkData::Fill(pattern) => {
+                let fill_len = len.min(FILL_BUFFER_SIZE as u64) as usize;
+                let fill: Vec<u8> = pattern.iter().copied().cycle().take(fill_len).collect();
+
+                let mut written = 0;
+                while written < len {
+                    let chunk_len = (len - written).min(fill.len() as u64) as usize;
+                    writer.write(offset + written, &fill[..chunk_len])?;
+                    written += chunk_len as u64;
+                }
+
+                let pattern_crc =
+                    boot::calculate_crc32(&pattern).with_context("failed to compute crc")?;
+                crc = crc32_combine(crc, crc32_repeat(pattern_crc, 4, len / 4), len);
+            }
+            ChunkData::DontCare => {
+                let zero_crc =
+                    boot::calculate_crc32(&[0; 4]).with_context("failed to compute crc")?;
+                crc = crc32_combine(crc, crc32_repeat(zero_crc, 4, len / 4), len);
+            }
+            ChunkData::Crc32(expected) => {
+                if expected != crc {
+                    return Err(Error::new(Status::CRC_ERROR, "sparse image crc32 mismatch"));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLK_SZ: u32 = 4;
+
+    fn chunk(chunk_type: u16, chunk_sz: u32, body: &[u8]) -> Vec<u8> {
+        let total_sz = (size_of::<ChunkHeader>() + body.len()) as u32;
+        let mut chunk = Vec::new();
+        chunk.extend(chunk_type.to_le_bytes());
+        chunk.extend(0u16.to_le_bytes());
+        chunk.extend(chunk_sz.to_le_bytes());
+        chunk.extend(total_sz.to_le_bytes());
+        chunk.extend(body);
+        chunk
+    }
+
+    fn image(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend(SPARSE_HEADER_MAGIC.to_le_bytes());
+        image.extend(1u16.to_le_bytes());
+        image.extend(0u16.to_le_bytes());
+        image.extend((size_of::<SparseHeader>() as u16).to_le_bytes());
+        image.extend((size_of::<ChunkHeader>() as u16).to_le_bytes());
+        image.extend(BLK_SZ.to_le_bytes());
+        image.extend(7u32.to_le_bytes());
+        image.extend((chunks.len() as u32).to_le_bytes());
+        image.extend(0u32.to_le_bytes());
+        image.extend(chunks.concat());
+        image
+    }
+
+    fn parse(image: &[u8]) -> Vec<Result<Chunk<'_>, &'static str>> {
+        Chunks::new(image, sparse_header(image).unwrap()).collect()
+    }
+
+    #[test]
+    fn crc32_combine_matches_zlib() {
+        // crc32("hello "), crc32("world") and crc32("hello world")
+        assert_eq!(crc32_combine(0xed81f9f6, 0x3a771143, 5), 0x0d4a1185);
+        assert_eq!(crc32_combine(0, 0x3a771143, 5), 0x3a771143);
+    }
+
+    #[test]
+    fn crc32_repeat_matches_zlib() {
+        // crc32 of 4 and 12 zero bytes
+        assert_eq!(crc32_repeat(0x2144df1c, 4, 3), 0x7bd5c66f);
+        // crc32 of 01 02 03 04, once and three times over
+        assert_eq!(crc32_repeat(0xb63cfbcd, 4, 3), 0xb19e38c6);
+        assert_eq!(crc32_repeat(0xb63cfbcd, 4, 1), 0xb63cfbcd);
+    }
+
+    #[test]
+    fn chunks_are_parsed_in_order() {
+        let image = image(&[
+            chunk(CHUNK_TYPE_RAW, 2, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            chunk(CHUNK_TYPE_FILL, 3, &[0xaa, 0xbb, 0xcc, 0xdd]),
+            chunk(CHUNK_TYPE_DONT_CARE, 2, &[]),
+            chunk(CHUNK_TYPE_CRC32, 0, &0x12345678u32.to_le_bytes()),
+        ]);
+
+        let chunks = parse(&image);
+        assert_eq!(chunks.len(), 4);
+
+        let chunks: Vec<_> = chunks.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.len))
+                .collect::<Vec<_>>(),
+            [(0, 8), (8, 12), (20, 8), (28, 0)]
+        );
+
+        assert!(matches!(
+            chunks[0].data,
+            ChunkData::Raw([1, 2, 3, 4, 5, 6, 7, 8])
+        ));
+        assert!(matches!(
+            chunks[1].data,
+            ChunkData::Fill([0xaa, 0xbb, 0xcc, 0xdd])
+        ));
+        assert!(matches!(chunks[2].data, ChunkData::DontCare));
+        assert!(matches!(chunks[3].data, ChunkData::Crc32(0x12345678)));
+    }
+
+    #[test]
+    fn truncated_chunk_is_rejected() {
+        let mut image = image(&[
+            chunk(CHUNK_TYPE_DONT_CARE, 1, &[]),
+            chunk(CHUNK_TYPE_RAW, 2, &[1, 2, 3, 4, 5, 6, 7, 8]),
+        ]);
+        image.truncate(image.len() - 1);
+
+        let chunks = parse(&image);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert_eq!(
+            chunks[1].as_ref().err().unwrap().status(),
+            Status::END_OF_FILE
+        );
+    }
+
+    #[test]
+    fn bad_total_sz_is_rejected() {
+        let first_error = |image: &[u8]| {
+            parse(image)
+                .into_iter()
+                .find_map(Result::err)
+                .map(|err| err.status())
+        };
+
+        let mut short = chunk(CHUNK_TYPE_DONT_CARE, 1, &[]);
+        short[8..12].copy_from_slice(&4u32.to_le_bytes());
+        assert_eq!(first_error(&image(&[short])), Some(Status::END_OF_FILE));
+
+        // The body must hold exactly chunk_sz blocks of raw data.
+        let raw = chunk(CHUNK_TYPE_RAW, 3, &[0; 8]);
+        assert_eq!(first_error(&image(&[raw])), Some(Status::INVALID_PARAMETER));
+
+        let fill = chunk(CHUNK_TYPE_FILL, 1, &[0; 2]);
+        assert_eq!(
+            first_error(&image(&[fill])),
+            Some(Status::INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn unknown_chunk_type_ends_iteration() {
+        let image = image(&[chunk(0xcac5, 1, &[]), chunk(CHUNK_TYPE_DONT_CARE, 1, &[])]);
+
+        let chunks = parse(&image);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].as_ref().err().unwrap().status(),
+            Status::UNSUPPORTED
+        );
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use log::info;
+
+use crate::error::FastbootResult;
+
+pub(crate) const MAX_RESPONSE_SIZE: usize = 64;
+
+/// Produces the data phase of an upload in chunks, given the offset of each.
+pub(crate) type FillFn<'a> = dyn FnMut(u64, &mut [u8]) -> FastbootResult + 'a;
+
+/// The link to the host that commands arrive on and responses and data
+/// phases go out over, keeping command handling independent of how the
+/// bytes are moved.
+pub(crate) trait FastbootTransport {
+    /// Waits for the next command from the host.
+    fn receive_command(&self) -> FastbootResult<&[u8]>;
+
+    /// Sends a single response packet of at most [`MAX_RESPONSE_SIZE`] bytes.
+    fn send_packet(&self, packet: &[u8]) -> FastbootResult;
+
+    /// Fills `target` from the data phase of a download.
+    fn receive_data(&self, target: &mut [u8]) -> FastbootResult;
+
+    /// Sends a data phase of `size` bytes, as produced by `fill`.
+    fn send_data(&self, size: u64, fill: &mut FillFn) -> FastbootResult;
+
+    /// Waits for everything sent so far to reach the host.
+    fn flush(&self) -> FastbootResult;
+
+    /// Whether the host is still around to receive a response to the
+    /// current command.
+    fn can_respond(&self) -> bool;
+
+    // Longer INFO and TEXT messages are split into several of the same kind,
+    // and a FAIL sends the leading part of its reason as INFO lines. Any other
+    // response, e.g. an OKAY carrying a getvar value, has a single packet
+    // for its payload, so it's cut short rather than split.
+    fn respond(&self, response: &str) -> FastbootResult {
+        let (kind, mut message) = response.split_at_checked(4).unwrap_or(("", response));
+        let continuation = match kind {
+            "INFO" | "TEXT" => Some(kind),
+            "FAIL" => Some("INFO"),
+            _ => None,
+        };
+
+        let mut packet = [0u8; MAX_RESPONSE_SIZE];
+        let mut send = |kind: &str, message: &str| {
+            let len = kind.len() + message.len();
+            packet[..kind.len()].copy_from_slice(kind.as_bytes());
+            packet[kind.len()..len].copy_from_slice(message.as_bytes());
+            self.send_packet(&packet[..len])
+        };
+
+        // The kind is either empty or four bytes long.
+        let max_len = MAX_RESPONSE_SIZE - kind.len();
+        while message.len() > max_len {
+            let (line, rest) = message.split_at(message.floor_char_boundary(max_len));
+
+            let Some(continuation) = continuation else {
+                info!("response truncated to {} bytes", kind.len() + line.len());
+                message = line;
+                break;
+            };
+
+            send(continuation, line)?;
+            message = rest;
+        }
+
+        send(kind, message)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::FastbootError;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use alloc::{format, vec};
+    use core::cell::{Cell, RefCell};
+
+    /// Plays back a fixed list of commands and records everything sent, so
+    /// command handling can run without any USB device.
+    #[derive(Default)]
+    pub(crate) struct MemoryTransport {
+        commands: Vec<Vec<u8>>,
+        pub(crate) download: Vec<u8>,
+        pub(crate) packets: RefCell<Vec<Vec<u8>>>,
+        pub(crate) upload: RefCell<Vec<u8>>,
+        next_command: Cell<usize>,
+    }
+
+    impl MemoryTransport {
+        pub(crate) fn new(commands: &[&[u8]]) -> Self {
+            Self {
+                commands: commands.iter().map(|command| command.to_vec()).collect(),
+                ..Default::default()
+            }
+        }
+
+        pub(crate) fn responses(&self) -> Vec<String> {
+            self.packets
+                .borrow()
+                .iter()
+                .map(|packet| String::from_utf8(packet.clone()).unwrap())
+                .collect()
+        }
+    }
+
+    impl FastbootTransport for MemoryTransport {
+        fn receive_command(&self) -> FastbootResult<&[u8]> {
+            let index = self.next_command.get();
+            let command = self
+                .commands
+                .get(index)
+                .ok_or(FastbootError::Disconnected)?;
+            self.next_command.set(index + 1);
+
+            Ok(command)
+        }
+
+        fn send_packet(&self, packet: &[u8]) -> FastbootResult {
+            assert!(packet.len() <= MAX_RESPONSE_SIZE);
+            self.packets.borrow_mut().push(packet.into());
+            Ok(())
+        }
+
+        fn receive_data(&self, target: &mut [u8]) -> FastbootResult {
+            let data = self
+                .download
+                .get(..target.len())
+                .ok_or(FastbootError::Disconnected)?;
+            target.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn send_data(&self, size: u64, fill: &mut FillFn) -> FastbootResult {
+            let mut data = vec![0; size as usize];
+            fill(0, &mut data)?;
+            self.upload.borrow_mut().extend(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> FastbootResult {
+            Ok(())
+        }
+
+        fn can_respond(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn long_fail_is_split_into_info_lines() {
+        let transport = MemoryTransport::default();
+        let reason = "x".repeat(100);
+
+        transport.respond(&format!("FAIL{reason}")).unwrap();
+
+        assert_eq!(
+            transport.responses(),
+            [
+                format!("INFO{}", &reason[..60]),
+                format!("FAIL{}", &reason[60..])
+            ]
+        );
+    }
+
+    #[test]
+    fn long_info_is_split_on_char_boundaries() {
+        let transport = MemoryTransport::default();
+        let message = "é".repeat(40);
+
+        transport.respond(&format!("INFO{message}")).unwrap();
+
+        let responses = transport.responses();
+        assert_eq!(responses.len(), 2);
+        assert!(responses
+            .iter()
+            .all(|response| response.starts_with("INFO")));
+        assert_eq!(responses.concat().replace("INFO", ""), message);
+    }
+
+    #[test]
+    fn long_okay_is_truncated_not_split() {
+        let transport = MemoryTransport::default();
+        let value = "v".repeat(100);
+
+        transport.respond(&format!("OKAY{value}")).unwrap();
+
+        assert_eq!(transport.responses(), [format!("OKAY{}", &value[..60])]);
+    }
+
+    #[test]
+    fn response_without_a_kind_is_sent_whole() {
+        let transport = MemoryTransport::default();
+
+        transport.respond("OK").unwrap();
+        transport
+            .respond(&format!("OKA\u{e9}{}", "x".repeat(100)))
+            .unwrap();
+
+        let responses = transport.responses();
+        assert_eq!(responses[0], "OK");
+        assert_eq!(responses[1].len(), MAX_RESPONSE_SIZE);
+        assert!(responses[1].starts_with("OKA\u{e9}"));
+    }
+}
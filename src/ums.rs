@@ -0,0 +1,602 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::info;
+use uefi::boot::{self, ScopedProtocol};
+use uefi::proto::media::block::BlockIO;
+use uefi::{Handle, Status};
+
+use crate::command::{required, Action, Context, FastbootCommand};
+use crate::error::{FastbootError, FastbootResult};
+use crate::partition::{self, get_protocol};
+use crate::slot;
+use crate::transport::FastbootTransport;
+use crate::usb_config::{UsbConfig, UsbFunction};
+use crate::usb_device::{
+    EfiUsbDeviceEvent, SessionState, TransferBuffer, UsbSession, ENDPOINT_IN, ENDPOINT_OUT,
+};
+use crate::usb_transport;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+// Large enough for a full packet at any speed, so that a CBW never overruns
+// the transfer it arrives in.
+const CBW_BUFFER_SIZE: usize = 1024;
+const TRANSFER_SIZE: usize = 64 * 1024;
+const HIGH_SPEED_MAX_PACKET_SIZE: usize = 512;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const READ_CAPACITY_16: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+}
+
+const NO_SENSE: Sense = Sense {
+    key: 0x0,
+    asc: 0x00,
+};
+const UNRECOVERED_READ_ERROR: Sense = Sense {
+    key: 0x3,
+    asc: 0x11,
+};
+const WRITE_ERROR: Sense = Sense {
+    key: 0x3,
+    asc: 0x0c,
+};
+const INVALID_COMMAND: Sense = Sense {
+    key: 0x5,
+    asc: 0x20,
+};
+const LBA_OUT_OF_RANGE: Sense = Sense {
+    key: 0x5,
+    asc: 0x21,
+};
+const INVALID_FIELD_IN_CDB: Sense = Sense {
+    key: 0x5,
+    asc: 0x24,
+};
+const WRITE_PROTECTED: Sense = Sense {
+    key: 0x7,
+    asc: 0x27,
+};
+
+struct Cbw {
+    tag: u32,
+    data_len: usize,
+    data_in: bool,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != CBW_LEN
+            || u32::from_le_bytes(data[0..4].try_into().unwrap()) != CBW_SIGNATURE
+        {
+            return None;
+        }
+
+        Some(Self {
+            tag: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            data_len: u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize,
+            data_in: data[12] & CBW_FLAG_DATA_IN != 0,
+            cb: data[15..31].try_into().unwrap(),
+        })
+    }
+
+    fn u16_at(&self, offset: usize) -> usize {
+        u16::from_be_bytes(self.cb[offset..offset + 2].try_into().unwrap()) as usize
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_be_bytes(self.cb[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_be_bytes(self.cb[offset..offset + 8].try_into().unwrap())
+    }
+
+    // Returns the first block and block count of a READ or WRITE.
+    fn range(&self) -> (u64, u64) {
+        match self.cb[0] {
+            READ_16 | WRITE_16 => (self.u64_at(2), self.u32_at(10) as u64),
+            _ => (self.u32_at(2) as u64, self.u16_at(7) as u64),
+        }
+    }
+}
+
+// Pads or truncates `value` to the fixed width ASCII fields of INQUIRY.
+fn inquiry_field(value: &str, len: usize) -> impl Iterator<Item = u8> + '_ {
+    value
+        .bytes()
+        .filter(|b| b.is_ascii_graphic() || *b == b' ')
+        .chain(core::iter::repeat(b' '))
+        .take(len)
+}
+
+/// A SCSI disk served over the bulk-only transport, backed by a BlockIO
+/// device. Class requests on the control endpoint never reach us, so the
+/// host falls back to a single LUN and recovers from errors by resetting
+/// the port.
+struct MassStorage<'a> {
+    usb_device: &'a UsbSession<'a>,
+    block_io: ScopedProtocol<BlockIO>,
+    media_id: u32,
+    block_size: usize,
+    blocks: u64,
+    read_only: bool,
+    inquiry: Vec<u8>,
+    sense: Sense,
+    ejected: bool,
+}
+
+impl<'a> MassStorage<'a> {
+    fn new(
+        usb_device: &'a UsbSession<'a>,
+        usb_config: &UsbConfig,
+        block_io: ScopedProtocol<BlockIO>,
+    ) -> Self {
+        let media = block_io.media();
+
+        let mut inquiry = vec![0x00, 0x80, 0x04, 0x02, 31, 0, 0, 0];
+        inquiry.extend(inquiry_field(&usb_config.manufacturer, 8));
+        inquiry.extend(inquiry_field(&usb_config.product, 16));
+        inquiry.extend(inquiry_field(
+            &format!("{:04x}", usb_config.device_release),
+            4,
+        ));
+
+        Self {
+            usb_device,
+            media_id: media.media_id(),
+            block_size: media.block_size() as usize,
+            blocks: media.last_block() + 1,
+            read_only: media.is_read_only(),
+            inquiry,
+            sense: NO_SENSE,
+            ejected: false,
+            block_io,
+        }
+    }
+
+    fn receive(&self, buffer: &TransferBuffer, len: usize) -> FastbootResult<&'static [u8]> {
+        self.usb_device.receive(buffer, len)?;
+
+        loop {
+            match self.usb_device.wait_event()? {
+                EfiUsbDeviceEvent::OutData(data) => return Ok(data),
+                EfiUsbDeviceEvent::OutError => return Err(FastbootError::TransferFailed),
+                EfiUsbDeviceEvent::Connected | EfiUsbDeviceEvent::Disconnected => {
+                    return Err(FastbootError::Disconnected)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> FastbootResult {
+        let mut buffer = self.usb_device.acquire_buffer(data.len())?;
+        buffer.as_mut_slice()[..data.len()].copy_from_slice(data);
+
+        self.usb_device.send_in(buffer, data.len())?;
+        usb_transport::wait_for_in_complete(self.usb_device)
+    }
+
+    // A data stage that ends short on a packet boundary isn't seen as ended
+    // by the host, so the endpoint is stalled for it to move on to the CSW.
+    // A zero length packet does the same for firmware that can't stall.
+    fn end_data_in(&self, cbw: &Cbw, sent: usize) -> FastbootResult {
+        if sent == cbw.data_len || !sent.is_multiple_of(HIGH_SPEED_MAX_PACKET_SIZE) {
+            return Ok(());
+        }
+
+        match self.usb_device.set_endpoint_stall_state(ENDPOINT_IN, true) {
+            Ok(()) => Ok(()),
+            Err(_) => self.send(&[]),
+        }
+    }
+
+    // Consumes the data stage of a command that isn't going to use it.
+    fn skip_data(&self, cbw: &Cbw) -> FastbootResult {
+        if cbw.data_len == 0 {
+            return Ok(());
+        }
+
+        if cbw.data_in {
+            return self.end_data_in(cbw, 0);
+        }
+
+        let buffer = self.usb_device.acquire_buffer(TRANSFER_SIZE)?;
+        let mut received = 0;
+        let result = loop {
+            if received >= cbw.data_len {
+                break Ok(());
+            }
+
+            match self.receive(&buffer, (cbw.data_len - received).min(TRANSFER_SIZE)) {
+                Ok([]) => break Ok(()),
+                Ok(data) => received += data.len(),
+                Err(err) => break Err(err),
+            }
+        };
+        self.usb_device.release_buffer(buffer);
+
+        result
+    }
+
+    fn check_range(&self, cbw: &Cbw) -> Result<(u64, usize), Sense> {
+        let (lba, count) = cbw.range();
+        if lba.checked_add(count).is_none_or(|end| end > self.blocks) {
+            return Err(LBA_OUT_OF_RANGE);
+        }
+
+        let len = count as usize * self.block_size;
+        if len != cbw.data_len || cbw.data_in != (cbw.cb[0] == READ_10 || cbw.cb[0] == READ_16) {
+            return Err(INVALID_FIELD_IN_CDB);
+        }
+
+        Ok((lba, len))
+    }
+
+    // Returns the number of bytes moved along with the command's outcome.
+    fn read(&self, cbw: &Cbw) -> FastbootResult<(usize, Result<(), Sense>)> {
+        let (lba, len) = match self.check_range(cbw) {
+            Ok(range) => range,
+            Err(sense) => return self.skip_data(cbw).map(|_| (0, Err(sense))),
+        };
+
+        let mut sent = 0;
+        while sent < len {
+            let chunk = (len - sent).min(TRANSFER_SIZE);
+            let mut buffer = self.usb_device.acquire_buffer(chunk)?;
+
+            let block = lba + (sent / self.block_size) as u64;
+            let read = self.block_io.read_blocks(
+                self.media_id,
+                block,
+                &mut buffer.as_mut_slice()[..chunk],
+            );
+            if let Err(err) = read {
+                info!("mass storage read at {block} failed: {:?}", err.status());
+                self.usb_device.release_buffer(buffer);
+                self.end_data_in(cbw, sent)?;
+                return Ok((sent, Err(UNRECOVERED_READ_ERROR)));
+            }
+
+            self.usb_device.send_in(buffer, chunk)?;
+            usb_transport::wait_for_in_complete(self.usb_device)?;
+            sent += chunk;
+        }
+
+        Ok((sent, Ok(())))
+    }
+
+    fn write(&mut self, cbw: &Cbw) -> FastbootResult<(usize, Result<(), Sense>)> {
+        let range = match self.read_only {
+            true => Err(WRITE_PROTECTED),
+            false => self.check_range(cbw),
+        };
+        let (lba, len) = match range {
+            Ok(range) => range,
+            Err(sense) => return self.skip_data(cbw).map(|_| (0, Err(sense))),
+        };
+
+        let buffer = self.usb_device.acquire_buffer(len.min(TRANSFER_SIZE))?;
+        let mut received = 0;
+        let mut result = Ok(());
+
+        while received < len {
+            let chunk = (len - received).min(TRANSFER_SIZE);
+            let data = match self.receive(&buffer, chunk) {
+                Ok(data) => data,
+                Err(err) => {
+                    self.usb_device.release_buffer(buffer);
+                    return Err(err);
+                }
+            };
+
+            // Whatever the host still sends after a failed write is taken
+            // in and dropped.
+            let block = lba + (received / self.block_size) as u64;
+            if result.is_ok() && data.len() == chunk {
+                if let Err(err) = self.block_io.write_blocks(self.media_id, block, data) {
+                    info!("mass storage write at {block} failed: {:?}", err.status());
+                    result = Err(WRITE_ERROR);
+                }
+            } else if result.is_ok() {
+                result = Err(WRITE_ERROR);
+            }
+
+            received += data.len();
+            if data.len() < chunk {
+                break;
+            }
+        }
+        self.usb_device.release_buffer(buffer);
+
+        Ok((received, result))
+    }
+
+    // Handles everything but READ and WRITE, returning the data to send
+    // back, if any.
+    fn command(&mut self, cbw: &Cbw) -> Result<Vec<u8>, Sense> {
+        let cb = &cbw.cb;
+
+        match cb[0] {
+            TEST_UNIT_READY | PREVENT_ALLOW_MEDIUM_REMOVAL | VERIFY_10 => Ok(Vec::new()),
+            REQUEST_SENSE => {
+                let sense = core::mem::replace(&mut self.sense, NO_SENSE);
+                let mut data = vec![0x70, 0, sense.key, 0, 0, 0, 0, 10, 0, 0, 0, 0, sense.asc];
+                data.resize(18, 0);
+                data.truncate(cb[4] as usize);
+                Ok(data)
+            }
+            INQUIRY if cb[1] & 0x1 != 0 => Err(INVALID_FIELD_IN_CDB),
+            INQUIRY => {
+                let mut data = self.inquiry.clone();
+                data.truncate(cbw.u16_at(3));
+                Ok(data)
+            }
+            MODE_SENSE_6 => {
+                let mut data = vec![3, 0, (self.read_only as u8) << 7, 0];
+                data.truncate(cb[4] as usize);
+                Ok(data)
+            }
+            MODE_SENSE_10 => {
+                let mut data = vec![0, 6, 0, (self.read_only as u8) << 7, 0, 0, 0, 0];
+                data.truncate(cbw.u16_at(7));
+                Ok(data)
+            }
+            START_STOP_UNIT => {
+                let load_eject = cb[4] & 0x2 != 0;
+                let start = cb[4] & 0x1 != 0;
+                if load_eject && !start {
+                    self.ejected = true;
+                }
+                Ok(Vec::new())
+            }
+            READ_FORMAT_CAPACITIES => {
+                let blocks = self.blocks.min(u32::MAX as u64) as u32;
+                let mut data = vec![0, 0, 0, 8];
+                data.extend_from_slice(&blocks.to_be_bytes());
+                data.extend_from_slice(&(self.block_size as u32 | 0x0200_0000).to_be_bytes());
+                data.truncate(cbw.u16_at(7));
+                Ok(data)
+            }
+            READ_CAPACITY_10 => {
+                let last_block = (self.blocks - 1).min(u32::MAX as u64) as u32;
+                let mut data = Vec::from(last_block.to_be_bytes());
+                data.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+                Ok(data)
+            }
+            SERVICE_ACTION_IN_16 if cb[1] & 0x1f == READ_CAPACITY_16 => {
+                let mut data = Vec::from((self.blocks - 1).to_be_bytes());
+                data.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+                data.resize(32, 0);
+                data.truncate(cbw.u32_at(10) as usize);
+                Ok(data)
+            }
+            SYNCHRONIZE_CACHE_10 => self
+                .block_io
+                .flush_blocks()
+                .map(|_| Vec::new())
+                .map_err(|_| WRITE_ERROR),
+            _ => Err(INVALID_COMMAND),
+        }
+    }
+
+    fn execute(&mut self, cbw: &Cbw) -> FastbootResult<(usize, Result<(), Sense>)> {
+        match cbw.cb[0] {
+            READ_10 | READ_16 => self.read(cbw),
+            WRITE_10 | WRITE_16 => self.write(cbw),
+            _ => match self.command(cbw) {
+                Ok(data) if cbw.data_in && cbw.data_len > 0 => {
+                    let len = data.len().min(cbw.data_len);
+                    if len > 0 {
+                        self.send(&data[..len])?;
+                    }
+                    self.end_data_in(cbw, len)?;
+                    Ok((len, Ok(())))
+                }
+                Ok(_) => self.skip_data(cbw).map(|_| (0, Ok(()))),
+                Err(sense) => self.skip_data(cbw).map(|_| (0, Err(sense))),
+            },
+        }
+    }
+
+    fn transaction(&mut self, cbw_buffer: &TransferBuffer) -> FastbootResult {
+        let data = self.receive(cbw_buffer, CBW_BUFFER_SIZE)?;
+        let Some(cbw) = Cbw::parse(data) else {
+            info!("ignoring invalid mass storage command block");
+            return Ok(());
+        };
+
+        let (transferred, result) = self.execute(&cbw)?;
+        if cbw.cb[0] != REQUEST_SENSE {
+            self.sense = result.err().unwrap_or(NO_SENSE);
+        }
+
+        let mut csw = Vec::with_capacity(13);
+        csw.extend_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw.extend_from_slice(&cbw.tag.to_le_bytes());
+        csw.extend_from_slice(&((cbw.data_len - transferred) as u32).to_le_bytes());
+        csw.push(match result {
+            Ok(()) => CSW_STATUS_PASSED,
+            Err(_) => CSW_STATUS_FAILED,
+        });
+
+        self.send(&csw)
+    }
+
+    // Serves commands until the host ejects the medium or goes away.
+    fn serve(&mut self) -> FastbootResult {
+        let cbw_buffer = self.usb_device.acquire_buffer(CBW_BUFFER_SIZE)?;
+        let mut connected = false;
+
+        let result = loop {
+            if self.ejected {
+                info!("mass storage ejected by host");
+                break Ok(());
+            }
+
+            match self.usb_device.state() {
+                SessionState::Idle if connected => {
+                    info!("mass storage host disconnected");
+                    break Ok(());
+                }
+                SessionState::Idle => {
+                    if let Err(err) = self.usb_device.wait_event() {
+                        break Err(err.into());
+                    }
+                    continue;
+                }
+                _ => connected = true,
+            }
+
+            match self.transaction(&cbw_buffer) {
+                Ok(()) | Err(FastbootError::Disconnected) => {}
+                Err(err) => info!("mass storage transaction failed: {err}"),
+            }
+        };
+
+        if self.usb_device.receive_pending() {
+            let _ = self.usb_device.abort(ENDPOINT_OUT);
+        }
+        self.usb_device.release_buffer(cbw_buffer);
+
+        result
+    }
+}
+
+// `disk` or `diskN` selects a whole disk, anything else a partition.
+fn find_target(target: &str) -> FastbootResult<Handle> {
+    if let Some(index) = target.strip_prefix("disk") {
+        let index = match index {
+            "" => 0,
+            index => index
+                .parse()
+                .map_err(|_| FastbootError::InvalidArgument("disk index"))?,
+        };
+
+        let disks = boot::find_handles::<BlockIO>()?
+            .into_iter()
+            .filter(|handle| {
+                get_protocol::<BlockIO>(*handle).is_ok_and(|block_io| {
+                    let media = block_io.media();
+                    !media.is_logical_partition() && media.is_media_present()
+                })
+            });
+
+        return disks
+            .into_iter()
+            .nth(index)
+            .ok_or(FastbootError::Failed(Status::NOT_FOUND, "disk not found"));
+    }
+
+    let name = slot::resolve_partition_name(target);
+    Ok(partition::find_partition(&name)?.handle())
+}
+
+fn handle_ums(
+    transport: &dyn FastbootTransport,
+    usb_device: &UsbSession,
+    usb_config: &UsbConfig,
+    target: &str,
+) -> FastbootResult {
+    let handle = find_target(target)?;
+    let block_io = boot::open_protocol_exclusive::<BlockIO>(handle)?;
+    if !block_io.media().is_media_present() {
+        return Err(FastbootError::Failed(Status::NO_MEDIA, "no media present"));
+    }
+
+    let mut storage = MassStorage::new(usb_device, usb_config, block_io);
+    info!(
+        "serving {target} as mass storage, {} blocks of {} bytes{}",
+        storage.blocks,
+        storage.block_size,
+        if storage.read_only { ", read-only" } else { "" }
+    );
+
+    transport.respond("OKAY")?;
+    let _ = transport.flush();
+
+    let descriptors = Rc::new(usb_config.function_descriptors(UsbFunction::MassStorage));
+    let result = usb_device
+        .reenumerate(descriptors)
+        .map_err(FastbootError::from)
+        .and_then(|_| storage.serve());
+
+    // The exclusive open disconnected the drivers on top of the device, so
+    // they're brought back for the partitions and file systems to reappear.
+    drop(storage);
+    if let Err(err) = boot::connect_controller(handle, None, None, true) {
+        info!("failed to reconnect {target}: {:?}", err.status());
+    }
+
+    usb_device.restore()?;
+    info!("mass storage done, back to fastboot");
+
+    result
+}
+
+/// `oem ums`, bound to the USB device it re-enumerates.
+pub(crate) struct UmsCommand<'a> {
+    usb_device: &'a UsbSession<'a>,
+    usb_config: &'a UsbConfig,
+}
+
+impl<'a> UmsCommand<'a> {
+    pub(crate) fn new(usb_device: &'a UsbSession<'a>, usb_config: &'a UsbConfig) -> Self {
+        Self {
+            usb_device,
+            usb_config,
+        }
+    }
+}
+
+impl FastbootCommand for UmsCommand<'_> {
+    fn name(&self) -> &str {
+        "ums"
+    }
+
+    fn description(&self) -> &str {
+        "oem ums <disk|partition> - expose storage as usb mass storage"
+    }
+
+    fn execute(&self, context: &mut Context, args: Option<&str>) -> FastbootResult<Action> {
+        handle_ums(
+            context.transport,
+            self.usb_device,
+            self.usb_config,
+            required(args)?,
+        )?;
+        Ok(Action::Continue)
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::proto::usb_device::*;
+use crate::usb_device::{
+    COMPOSITE_CONFIG_DESCRIPTOR_TREE, CONFIG_DESCRIPTOR_TREE, MASS_STORAGE_CONFIG_DESCRIPTOR_TREE,
+    SUPERSPEED_COMPOSITE_CONFIG_DESCRIPTOR_TREE, SUPERSPEED_CONFIG_DESCRIPTOR_TREE,
+    SUPERSPEED_MASS_STORAGE_CONFIG_DESCRIPTOR_TREE,
+};
+
+const DEFAULT_VENDOR_ID: u16 = 0x18d1;
+const DEFAULT_PRODUCT_ID: u16 = 0xd00d;
+const DEFAULT_DEVICE_RELEASE: u16 = 0x100;
+const DEFAULT_MANUFACTURER: &str = "Google";
+const DEFAULT_PRODUCT: &str = "Android";
+const DEFAULT_INTERFACE: &str = "fastboot";
+const SERIAL_INTERFACE: &str = "fastboot console";
+const MASS_STORAGE_INTERFACE: &str = "mass storage";
+
+// Interface association descriptors require the device to announce the
+// miscellaneous class with the common class subclass and IAD protocol.
+const USB_CLASS_MISC: u8 = 0xef;
+const USB_MISC_SUBCLASS_COMMON: u8 = 0x2;
+const USB_MISC_PROTOCOL_IAD: u8 = 0x1;
+
+const USB_DESC_TYPE_STRING: u8 = 3;
+const LANGID_EN_US: u16 = 0x0409;
+const MAX_STRING_DESCRIPTOR_LEN: usize = 255;
+
+pub(crate) const STRING_INDEX_MANUFACTURER: u8 = 1;
+pub(crate) const STRING_INDEX_PRODUCT: u8 = 2;
+pub(crate) const STRING_INDEX_SERIAL_NUMBER: u8 = 3;
+pub(crate) const STRING_INDEX_INTERFACE: u8 = 4;
+pub(crate) const STRING_INDEX_SERIAL_INTERFACE: u8 = 5;
+
+fn parse_id(value: Option<&str>, default: u16) -> u16 {
+    value
+        .and_then(|value| u16::from_str_radix(value.trim_start_matches("0x"), 16).ok())
+        .unwrap_or(default)
+}
+
+// String descriptors hold UTF-16LE without a terminator, and bLength is a
+// byte count covering the two byte header, which limits the string to 126
+// code units.
+fn string_descriptor(string: &str) -> Vec<u8> {
+    let mut descriptor = Vec::from([0, USB_DESC_TYPE_STRING]);
+
+    for c in string.chars() {
+        let mut units = [0u16; 2];
+        let units = c.encode_utf16(&mut units);
+        if descriptor.len() + units.len() * 2 > MAX_STRING_DESCRIPTOR_LEN {
+            break;
+        }
+
+        for unit in units {
+            descriptor.extend_from_slice(&unit.to_le_bytes());
+        }
+    }
+
+    descriptor[0] = descriptor.len() as u8;
+    descriptor
+}
+
+fn language_descriptor() -> Vec<u8> {
+    let [lo, hi] = LANGID_EN_US.to_le_bytes();
+    Vec::from([4, USB_DESC_TYPE_STRING, lo, hi])
+}
+
+/// The functions the device can enumerate as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum UsbFunction {
+    Fastboot,
+    MassStorage,
+}
+
+/// The identity the device presents on the bus. Defaults to that of an
+/// Android device in fastboot mode, overridable at build time through the
+/// FASTBOOT_USB_VID, FASTBOOT_USB_PID, FASTBOOT_USB_MANUFACTURER and
+/// FASTBOOT_USB_PRODUCT environment variables. Setting FASTBOOT_USB_CONSOLE
+/// to 1 adds a CDC-ACM interface carrying the console.
+pub(crate) struct UsbConfig {
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+    pub(crate) device_release: u16,
+    pub(crate) manufacturer: String,
+    pub(crate) product: String,
+    pub(crate) interface: String,
+    pub(crate) serial_number: String,
+    pub(crate) serial_console: bool,
+}
+
+impl UsbConfig {
+    pub(crate) fn new(serial_number: String) -> Self {
+        Self {
+            vendor_id: parse_id(option_env!("FASTBOOT_USB_VID"), DEFAULT_VENDOR_ID),
+            product_id: parse_id(option_env!("FASTBOOT_USB_PID"), DEFAULT_PRODUCT_ID),
+            device_release: DEFAULT_DEVICE_RELEASE,
+            manufacturer: option_env!("FASTBOOT_USB_MANUFACTURER")
+                .unwrap_or(DEFAULT_MANUFACTURER)
+                .into(),
+            product: option_env!("FASTBOOT_USB_PRODUCT")
+                .unwrap_or(DEFAULT_PRODUCT)
+                .into(),
+            interface: DEFAULT_INTERFACE.into(),
+            serial_number,
+            serial_console: option_env!("FASTBOOT_USB_CONSOLE") == Some("1"),
+        }
+    }
+
+    // Only the composite configuration needs a device class; otherwise the
+    // class is given per interface.
+    fn device_class(&self, function: UsbFunction) -> (u8, u8, u8) {
+        if self.has_serial_console(function) {
+            (
+                USB_CLASS_MISC,
+                USB_MISC_SUBCLASS_COMMON,
+                USB_MISC_PROTOCOL_IAD,
+            )
+        } else {
+            (0, 0, 0)
+        }
+    }
+
+    fn has_serial_console(&self, function: UsbFunction) -> bool {
+        function == UsbFunction::Fastboot && self.serial_console
+    }
+
+    fn device_descriptor(
+        &self,
+        function: UsbFunction,
+        bcd_usb: u16,
+        max_packet_size0: u8,
+    ) -> UsbDeviceDescriptor {
+        let (class, subclass, protocol) = self.device_class(function);
+
+        UsbDeviceDescriptor {
+            bLength: USB_DEVICE_DESCRIPTOR_LEN,
+            bDescriptorType: USB_DEVICE_DESCRIPTOR_TYPE,
+            bcdUSB: bcd_usb,
+            bDeviceClass: class,
+            bDeviceSubClass: subclass,
+            bDeviceProtocol: protocol,
+            bMaxPacketSize0: max_packet_size0,
+            idVendor: self.vendor_id,
+            idProduct: self.product_id,
+            bcdDevice: self.device_release,
+            iManufacturer: STRING_INDEX_MANUFACTURER,
+            iProduct: STRING_INDEX_PRODUCT,
+            iSerialNumber: STRING_INDEX_SERIAL_NUMBER,
+            bNumConfigurations: 1,
+        }
+    }
+
+    fn device_qualifier(&self, function: UsbFunction) -> UsbDeviceQualifierDescriptor {
+        let (class, subclass, protocol) =
+            if function == UsbFunction::Fastboot && !self.serial_console {
+                (0xff, 0xff, 0xff)
+            } else {
+                self.device_class(function)
+            };
+
+        UsbDeviceQualifierDescriptor {
+            bLength: USB_DEVICE_QUALIFIER_DESCRIPTOR_LEN,
+            bDescriptorType: USB_DEVICE_QUALIFIER_DESCRIPTOR_TYPE,
+            bcdUSB: 0x200,
+            bDeviceClass: class,
+            bDeviceSubClass: subclass,
+            bDeviceProtocol: protocol,
+            bMaxPacketSize0: 64,
+            bNumConfigurations: 1,
+            bReserved: 0,
+        }
+    }
+
+    pub(crate) fn descriptors(&self) -> UsbDescriptors {
+        self.function_descriptors(UsbFunction::Fastboot)
+    }
+
+    pub(crate) fn function_descriptors(&self, function: UsbFunction) -> UsbDescriptors {
+        let serial_console = self.has_serial_console(function);
+        let interface = match function {
+            UsbFunction::Fastboot => &self.interface,
+            UsbFunction::MassStorage => MASS_STORAGE_INTERFACE,
+        };
+
+        let mut strings: Vec<Vec<u8>> = Vec::from([
+            language_descriptor(),
+            string_descriptor(&self.manufacturer),
+            string_descriptor(&self.product),
+            string_descriptor(&self.serial_number),
+            string_descriptor(interface),
+        ]);
+        if serial_console {
+            strings.push(string_descriptor(SERIAL_INTERFACE));
+        }
+        let string_pointers = strings.iter().map(|string| string.as_ptr()).collect();
+
+        let (config_descriptor_trees, superspeed_config_descriptor_trees) = match function {
+            UsbFunction::Fastboot if serial_console => (
+                [
+                    (&COMPOSITE_CONFIG_DESCRIPTOR_TREE as *const CompositeConfigDescriptorTree)
+                        .cast(),
+                ],
+                [(&SUPERSPEED_COMPOSITE_CONFIG_DESCRIPTOR_TREE
+                    as *const SuperSpeedCompositeConfigDescriptorTree)
+                    .cast()],
+            ),
+            UsbFunction::Fastboot => (
+                [&CONFIG_DESCRIPTOR_TREE as *const _],
+                [&SUPERSPEED_CONFIG_DESCRIPTOR_TREE as *const _],
+            ),
+            UsbFunction::MassStorage => (
+                [&MASS_STORAGE_CONFIG_DESCRIPTOR_TREE as *const _],
+                [&SUPERSPEED_MASS_STORAGE_CONFIG_DESCRIPTOR_TREE as *const _],
+            ),
+        };
+
+        UsbDescriptors {
+            device: self.device_descriptor(function, 0x210, 64),
+            superspeed_device: self.device_descriptor(function, 0x300, 9),
+            device_qualifier: self.device_qualifier(function),
+            config_descriptor_trees,
+            superspeed_config_descriptor_trees,
+            serial_console,
+            strings,
+            string_pointers,
+        }
+    }
+}
+
+/// Descriptors generated from a [`UsbConfig`], kept alive for as long as the
+/// firmware may hand them out.
+pub(crate) struct UsbDescriptors {
+    pub(crate) device: UsbDeviceDescriptor,
+    pub(crate) superspeed_device: UsbDeviceDescriptor,
+    pub(crate) device_qualifier: UsbDeviceQualifierDescriptor,
+    pub(crate) config_descriptor_trees: [*const ConfigDescriptorTree; 1],
+    pub(crate) superspeed_config_descriptor_trees: [*const SuperSpeedConfigDescriptorTree; 1],
+    pub(crate) serial_console: bool,
+    strings: Vec<Vec<u8>>,
+    string_pointers: Vec<*const u8>,
+}
+
+impl UsbDescriptors {
+    pub(crate) fn string_descriptor_count(&self) -> u8 {
+        self.strings.len() as u8
+    }
+
+    pub(crate) fn string_descriptors(&self) -> *const *const u8 {
+        self.string_pointers.as_ptr()
+    }
+}
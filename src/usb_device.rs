@@ -1,67 +1,40 @@
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::ops::Deref;
 use core::ptr::{self, slice_from_raw_parts};
-use uefi::{proto::unsafe_protocol, CStr16, Result, StatusExt};
+use core::slice;
+use log::info;
+use uefi::boot::{self, EventType, ScopedProtocol, TimerTrigger, Tpl};
+use uefi::Event;
+use uefi::{proto::unsafe_protocol, Guid, Result, ResultExt, Status, StatusExt};
 
+use crate::conout::ConOutMirror;
+use crate::getvar::VariableProvider;
 use crate::proto::usb_device::*;
+use crate::usb_config::{UsbDescriptors, STRING_INDEX_INTERFACE, STRING_INDEX_SERIAL_INTERFACE};
 
 pub const ENDPOINT_IN: u8 = 0x81;
 pub const ENDPOINT_OUT: u8 = 0x1;
+pub const SERIAL_ENDPOINT_IN: u8 = 0x82;
+pub const SERIAL_ENDPOINT_OUT: u8 = 0x2;
+pub const SERIAL_ENDPOINT_NOTIFY: u8 = 0x83;
+
+const SERIAL_CONTROL_INTERFACE: u8 = 1;
+const SERIAL_DATA_INTERFACE: u8 = 2;
 
 #[derive(Debug)]
 #[repr(transparent)]
 #[unsafe_protocol(EfiUsbDeviceProtocol::GUID)]
 pub struct EfiUsbDevice(EfiUsbDeviceProtocol);
 
-const DEVICE_DESCRIPTOR: UsbDeviceDescriptor = UsbDeviceDescriptor {
-    bLength: USB_DEVICE_DESCRIPTOR_LEN,
-    bDescriptorType: USB_DEVICE_DESCRIPTOR_TYPE,
-    bcdUSB: 0x210,
-    bDeviceClass: 0,
-    bDeviceSubClass: 0,
-    bDeviceProtocol: 0,
-    bMaxPacketSize0: 64,
-    idVendor: 0x18d1,
-    idProduct: 0xd00d,
-    bcdDevice: 0x100,
-    iManufacturer: 1,
-    iProduct: 2,
-    iSerialNumber: 3,
-    bNumConfigurations: 1,
-};
-
-const SUPERSPEED_DEVICE_DESCRIPTOR: UsbDeviceDescriptor = UsbDeviceDescriptor {
-    bLength: USB_DEVICE_DESCRIPTOR_LEN,
-    bDescriptorType: USB_DEVICE_DESCRIPTOR_TYPE,
-    bcdUSB: 0x300,
-    bDeviceClass: 0,
-    bDeviceSubClass: 0,
-    bDeviceProtocol: 0,
-    bMaxPacketSize0: 9,
-    idVendor: 0x18d1,
-    idProduct: 0xd00d,
-    bcdDevice: 0x100,
-    iManufacturer: 1,
-    iProduct: 2,
-    iSerialNumber: 3,
-    bNumConfigurations: 1,
-};
-
-const DEVICE_QUALIFIER: UsbDeviceQualifierDescriptor = UsbDeviceQualifierDescriptor {
-    bLength: USB_DEVICE_QUALIFIER_DESCRIPTOR_LEN,
-    bDescriptorType: USB_DEVICE_QUALIFIER_DESCRIPTOR_TYPE,
-    bcdUSB: 0x200,
-    bDeviceClass: 0xff,
-    bDeviceSubClass: 0xff,
-    bDeviceProtocol: 0xff,
-    bMaxPacketSize0: 64,
-    bNumConfigurations: 1,
-    bReserved: 0,
-};
-
-const CONFIG_DESCRIPTOR_TREE: ConfigDescriptorTree = ConfigDescriptorTree {
+pub(crate) const CONFIG_DESCRIPTOR_TREE: ConfigDescriptorTree = ConfigDescriptorTree {
     config_descriptor: UsbConfigDescriptor {
         bLength: USB_CONFIG_DESCRIPTOR_LEN,
         bDescriptorType: USB_CONFIG_DESCRIPTOR_TYPE,
@@ -84,7 +57,7 @@ const CONFIG_DESCRIPTOR_TREE: ConfigDescriptorTree = ConfigDescriptorTree {
         bInterfaceClass: 0xff,
         bInterfaceSubClass: 0x42,
         bInterfaceProtocol: 0x03,
-        iInterface: 4,
+        iInterface: STRING_INDEX_INTERFACE,
     },
     endpoint0_descriptor: UsbEndpointDescriptor {
         bLength: USB_ENDPOINT_DESCRIPTOR_LEN,
@@ -104,9 +77,7 @@ const CONFIG_DESCRIPTOR_TREE: ConfigDescriptorTree = ConfigDescriptorTree {
     },
 };
 
-const CONFIG_DESCRIPTOR_TREES: [*const ConfigDescriptorTree; 1] = [&CONFIG_DESCRIPTOR_TREE];
-
-const SUPERSPEED_CONFIG_DESCRIPTOR_TREE: SuperSpeedConfigDescriptorTree =
+pub(crate) const SUPERSPEED_CONFIG_DESCRIPTOR_TREE: SuperSpeedConfigDescriptorTree =
     SuperSpeedConfigDescriptorTree {
         config_descriptor: UsbConfigDescriptor {
             bLength: USB_CONFIG_DESCRIPTOR_LEN,
@@ -132,7 +103,7 @@ const SUPERSPEED_CONFIG_DESCRIPTOR_TREE: SuperSpeedConfigDescriptorTree =
             bInterfaceClass: 0xff,
             bInterfaceSubClass: 0x42,
             bInterfaceProtocol: 0x03,
-            iInterface: 4,
+            iInterface: STRING_INDEX_INTERFACE,
         },
         endpoint0_descriptor: UsbEndpointDescriptor {
             bLength: USB_ENDPOINT_DESCRIPTOR_LEN,
@@ -166,9 +137,204 @@ const SUPERSPEED_CONFIG_DESCRIPTOR_TREE: SuperSpeedConfigDescriptorTree =
         },
     };
 
-const SUPERSPEED_CONFIG_DESCRIPTOR_TREES: [*const SuperSpeedConfigDescriptorTree; 1] =
-    [&SUPERSPEED_CONFIG_DESCRIPTOR_TREE];
+/// A single SCSI bulk-only transport interface on the fastboot endpoints.
+pub(crate) const MASS_STORAGE_CONFIG_DESCRIPTOR_TREE: ConfigDescriptorTree = ConfigDescriptorTree {
+    interface_descriptor: MASS_STORAGE_INTERFACE_DESCRIPTOR,
+    ..CONFIG_DESCRIPTOR_TREE
+};
+
+pub(crate) const SUPERSPEED_MASS_STORAGE_CONFIG_DESCRIPTOR_TREE: SuperSpeedConfigDescriptorTree =
+    SuperSpeedConfigDescriptorTree {
+        interface_descriptor: MASS_STORAGE_INTERFACE_DESCRIPTOR,
+        ..SUPERSPEED_CONFIG_DESCRIPTOR_TREE
+    };
+
+const MASS_STORAGE_INTERFACE_DESCRIPTOR: UsbInterfaceDescriptor = UsbInterfaceDescriptor {
+    bInterfaceClass: USB_CLASS_MASS_STORAGE,
+    bInterfaceSubClass: USB_MASS_STORAGE_SUBCLASS_SCSI,
+    bInterfaceProtocol: USB_MASS_STORAGE_PROTOCOL_BULK_ONLY,
+    ..CONFIG_DESCRIPTOR_TREE.interface_descriptor
+};
+
+const SERIAL_ASSOCIATION_DESCRIPTOR: UsbInterfaceAssociationDescriptor =
+    UsbInterfaceAssociationDescriptor {
+        bLength: USB_INTERFACE_ASSOCIATION_DESCRIPTOR_LEN,
+        bDescriptorType: USB_INTERFACE_ASSOCIATION_DESCRIPTOR_TYPE,
+        bFirstInterface: SERIAL_CONTROL_INTERFACE,
+        bInterfaceCount: 2,
+        bFunctionClass: USB_CLASS_CDC,
+        bFunctionSubClass: USB_CDC_SUBCLASS_ACM,
+        bFunctionProtocol: 0,
+        iFunction: STRING_INDEX_SERIAL_INTERFACE,
+    };
+
+const SERIAL_CONTROL_INTERFACE_DESCRIPTOR: UsbInterfaceDescriptor = UsbInterfaceDescriptor {
+    bLength: USB_INTERFACE_DESCRIPTOR_LEN,
+    bDescriptorType: USB_INTERFACE_DESCRIPTOR_TYPE,
+    bInterfaceNumber: SERIAL_CONTROL_INTERFACE,
+    bAlternateSetting: 0,
+    bNumEndpoints: 1,
+    bInterfaceClass: USB_CLASS_CDC,
+    bInterfaceSubClass: USB_CDC_SUBCLASS_ACM,
+    bInterfaceProtocol: 0,
+    iInterface: STRING_INDEX_SERIAL_INTERFACE,
+};
+
+const SERIAL_HEADER_DESCRIPTOR: UsbCdcHeaderDescriptor = UsbCdcHeaderDescriptor {
+    bLength: USB_CDC_HEADER_DESCRIPTOR_LEN,
+    bDescriptorType: USB_CDC_CS_INTERFACE_DESCRIPTOR_TYPE,
+    bDescriptorSubtype: USB_CDC_HEADER_DESCRIPTOR_SUBTYPE,
+    bcdCDC: 0x110,
+};
+
+const SERIAL_CALL_MANAGEMENT_DESCRIPTOR: UsbCdcCallManagementDescriptor =
+    UsbCdcCallManagementDescriptor {
+        bLength: USB_CDC_CALL_MANAGEMENT_DESCRIPTOR_LEN,
+        bDescriptorType: USB_CDC_CS_INTERFACE_DESCRIPTOR_TYPE,
+        bDescriptorSubtype: USB_CDC_CALL_MANAGEMENT_DESCRIPTOR_SUBTYPE,
+        bmCapabilities: 0,
+        bDataInterface: SERIAL_DATA_INTERFACE,
+    };
+
+// Class requests on the control endpoint never reach us, so no line coding
+// or control line state capabilities are advertised. Hosts then treat
+// failures of those requests as harmless.
+const SERIAL_ACM_DESCRIPTOR: UsbCdcAcmDescriptor = UsbCdcAcmDescriptor {
+    bLength: USB_CDC_ACM_DESCRIPTOR_LEN,
+    bDescriptorType: USB_CDC_CS_INTERFACE_DESCRIPTOR_TYPE,
+    bDescriptorSubtype: USB_CDC_ACM_DESCRIPTOR_SUBTYPE,
+    bmCapabilities: 0,
+};
+
+const SERIAL_UNION_DESCRIPTOR: UsbCdcUnionDescriptor = UsbCdcUnionDescriptor {
+    bLength: USB_CDC_UNION_DESCRIPTOR_LEN,
+    bDescriptorType: USB_CDC_CS_INTERFACE_DESCRIPTOR_TYPE,
+    bDescriptorSubtype: USB_CDC_UNION_DESCRIPTOR_SUBTYPE,
+    bControlInterface: SERIAL_CONTROL_INTERFACE,
+    bSubordinateInterface0: SERIAL_DATA_INTERFACE,
+};
+
+const SERIAL_DATA_INTERFACE_DESCRIPTOR: UsbInterfaceDescriptor = UsbInterfaceDescriptor {
+    bLength: USB_INTERFACE_DESCRIPTOR_LEN,
+    bDescriptorType: USB_INTERFACE_DESCRIPTOR_TYPE,
+    bInterfaceNumber: SERIAL_DATA_INTERFACE,
+    bAlternateSetting: 0,
+    bNumEndpoints: 2,
+    bInterfaceClass: USB_CLASS_CDC_DATA,
+    bInterfaceSubClass: 0,
+    bInterfaceProtocol: 0,
+    iInterface: 0,
+};
+
+const fn serial_endpoint(
+    address: u8,
+    attributes: u8,
+    max_packet_size: u16,
+) -> UsbEndpointDescriptor {
+    UsbEndpointDescriptor {
+        bLength: USB_ENDPOINT_DESCRIPTOR_LEN,
+        bDescriptorType: USB_ENDPOINT_DESCRIPTOR_TYPE,
+        bEndpointAddress: address,
+        bmAttributes: attributes,
+        wMaxPacketSize: max_packet_size,
+        bInterval: if attributes == USB_ENDPOINT_TYPE_INTERRUPT {
+            9
+        } else {
+            0
+        },
+    }
+}
+
+const fn superspeed_companion(bytes_per_interval: u16) -> UsbSuperSpeedCompanionDescriptor {
+    UsbSuperSpeedCompanionDescriptor {
+        bLength: USB_SUPER_SPEED_COMPANION_DESCRIPTOR_LEN,
+        bDescriptorType: USB_SUPER_SPEED_COMPANION_DESCRIPTOR_TYPE,
+        bMaxBurst: 0,
+        bmAttributes: 0,
+        wBytesPerInterval: bytes_per_interval,
+    }
+}
+
+/// Fastboot followed by a CDC-ACM function carrying the console.
+pub(crate) const COMPOSITE_CONFIG_DESCRIPTOR_TREE: CompositeConfigDescriptorTree =
+    CompositeConfigDescriptorTree {
+        config_descriptor: UsbConfigDescriptor {
+            wTotalLength: size_of::<CompositeConfigDescriptorTree>() as u16,
+            bNumInterfaces: 3,
+            ..CONFIG_DESCRIPTOR_TREE.config_descriptor
+        },
+        interface_descriptor: CONFIG_DESCRIPTOR_TREE.interface_descriptor,
+        endpoint0_descriptor: CONFIG_DESCRIPTOR_TREE.endpoint0_descriptor,
+        endpoint1_descriptor: CONFIG_DESCRIPTOR_TREE.endpoint1_descriptor,
+        serial_association_descriptor: SERIAL_ASSOCIATION_DESCRIPTOR,
+        serial_control_interface_descriptor: SERIAL_CONTROL_INTERFACE_DESCRIPTOR,
+        serial_header_descriptor: SERIAL_HEADER_DESCRIPTOR,
+        serial_call_management_descriptor: SERIAL_CALL_MANAGEMENT_DESCRIPTOR,
+        serial_acm_descriptor: SERIAL_ACM_DESCRIPTOR,
+        serial_union_descriptor: SERIAL_UNION_DESCRIPTOR,
+        serial_notify_endpoint_descriptor: serial_endpoint(
+            SERIAL_ENDPOINT_NOTIFY,
+            USB_ENDPOINT_TYPE_INTERRUPT,
+            16,
+        ),
+        serial_data_interface_descriptor: SERIAL_DATA_INTERFACE_DESCRIPTOR,
+        serial_in_endpoint_descriptor: serial_endpoint(
+            SERIAL_ENDPOINT_IN,
+            USB_ENDPOINT_TYPE_BULK,
+            512,
+        ),
+        serial_out_endpoint_descriptor: serial_endpoint(
+            SERIAL_ENDPOINT_OUT,
+            USB_ENDPOINT_TYPE_BULK,
+            512,
+        ),
+    };
+
+pub(crate) const SUPERSPEED_COMPOSITE_CONFIG_DESCRIPTOR_TREE:
+    SuperSpeedCompositeConfigDescriptorTree = SuperSpeedCompositeConfigDescriptorTree {
+    config_descriptor: UsbConfigDescriptor {
+        wTotalLength: size_of::<SuperSpeedCompositeConfigDescriptorTree>() as u16,
+        bNumInterfaces: 3,
+        ..SUPERSPEED_CONFIG_DESCRIPTOR_TREE.config_descriptor
+    },
+    interface_descriptor: SUPERSPEED_CONFIG_DESCRIPTOR_TREE.interface_descriptor,
+    endpoint0_descriptor: SUPERSPEED_CONFIG_DESCRIPTOR_TREE.endpoint0_descriptor,
+    superspeed_endpoint0_companion_descriptor: SUPERSPEED_CONFIG_DESCRIPTOR_TREE
+        .superspeed_ednpoint0_compaion_descriptor,
+    endpoint1_descriptor: SUPERSPEED_CONFIG_DESCRIPTOR_TREE.endpoint1_descriptor,
+    superspeed_endpoint1_companion_descriptor: SUPERSPEED_CONFIG_DESCRIPTOR_TREE
+        .superspeed_ednpoint1_compaion_descriptor,
+    serial_association_descriptor: SERIAL_ASSOCIATION_DESCRIPTOR,
+    serial_control_interface_descriptor: SERIAL_CONTROL_INTERFACE_DESCRIPTOR,
+    serial_header_descriptor: SERIAL_HEADER_DESCRIPTOR,
+    serial_call_management_descriptor: SERIAL_CALL_MANAGEMENT_DESCRIPTOR,
+    serial_acm_descriptor: SERIAL_ACM_DESCRIPTOR,
+    serial_union_descriptor: SERIAL_UNION_DESCRIPTOR,
+    serial_notify_endpoint_descriptor: serial_endpoint(
+        SERIAL_ENDPOINT_NOTIFY,
+        USB_ENDPOINT_TYPE_INTERRUPT,
+        16,
+    ),
+    serial_notify_companion_descriptor: superspeed_companion(16),
+    serial_data_interface_descriptor: SERIAL_DATA_INTERFACE_DESCRIPTOR,
+    serial_in_endpoint_descriptor: serial_endpoint(
+        SERIAL_ENDPOINT_IN,
+        USB_ENDPOINT_TYPE_BULK,
+        1024,
+    ),
+    serial_in_companion_descriptor: superspeed_companion(0),
+    serial_out_endpoint_descriptor: serial_endpoint(
+        SERIAL_ENDPOINT_OUT,
+        USB_ENDPOINT_TYPE_BULK,
+        1024,
+    ),
+    serial_out_companion_descriptor: superspeed_companion(0),
+};
 
+// There's no Microsoft OS 2.0 platform capability here: Windows follows it up
+// with a vendor request for the descriptor set, which the firmware answers
+// itself instead of passing it on, so announcing it would only make
+// enumeration log a failed request.
 const BINARY_OBJECT_STORE: BinaryObjectStore = BinaryObjectStore {
     descriptor: UsbBinaryObjectStoreDescriptor {
         bLength: USB_BINARY_OBJECT_STORE_DESCRIPTOR_LEN,
@@ -207,66 +373,12 @@ const BINARY_OBJECT_STORE: BinaryObjectStore = BinaryObjectStore {
     },
 };
 
-const USB_DESC_TYPE_STRING: u8 = 3;
-
-const STR0_DESCRIPTOR: [u8; 4] = [4, USB_DESC_TYPE_STRING, 0x09, 0x04];
-
-const STR_MANUFACTURER_DESCRIPTOR: [u8; 14] = [
-    14,
-    USB_DESC_TYPE_STRING,
-    b'G',
-    0,
-    b'o',
-    0,
-    b'o',
-    0,
-    b'g',
-    0,
-    b'l',
-    0,
-    b'e',
-    0,
-];
-
-const STR_INTERFACE_DESCRIPTOR: [u8; 18] = [
-    18,
-    USB_DESC_TYPE_STRING,
-    b'f',
-    0,
-    b'a',
-    0,
-    b's',
-    0,
-    b't',
-    0,
-    b'b',
-    0,
-    b'o',
-    0,
-    b'o',
-    0,
-    b't',
-    0,
-];
-
-const PRODUCT_DESCRIPTOR: [u8; 16] = [
-    16,
-    USB_DESC_TYPE_STRING,
-    b'A',
-    0,
-    b'n',
-    0,
-    b'd',
-    0,
-    b'r',
-    0,
-    b'o',
-    0,
-    b'i',
-    0,
-    b'd',
-    0,
-];
+#[derive(Debug)]
+pub struct OemEvent {
+    pub guid: Guid,
+    pub version: u64,
+    pub data: Vec<u8>,
+}
 
 #[derive(Debug)]
 pub enum EfiUsbDeviceEvent {
@@ -274,41 +386,25 @@ pub enum EfiUsbDeviceEvent {
     Connected,
     Disconnected,
     OutData(&'static [u8]),
+    OutError,
+    InComplete,
+    InError,
+    SerialInComplete,
+    SerialInError,
+    Oem(OemEvent),
 }
 
-#[allow(dead_code)]
 impl EfiUsbDevice {
-    fn build_string_descriptors(serial_number: &CStr16) -> [*const u8; 5] {
-        let serial_number = serial_number.as_bytes();
-        let length = serial_number.len();
-
-        let mut serial_descriptor = [0u8; 32];
-        serial_descriptor[0] = length as u8;
-        serial_descriptor[1] = USB_DESC_TYPE_STRING;
-        serial_descriptor[2..2 + length].copy_from_slice(serial_number);
-
-        let serial_descriptor = Box::new(serial_descriptor);
-
-        [
-            STR0_DESCRIPTOR.as_ptr(),
-            STR_MANUFACTURER_DESCRIPTOR.as_ptr(),
-            PRODUCT_DESCRIPTOR.as_ptr(),
-            Box::into_raw(serial_descriptor) as *const u8,
-            STR_INTERFACE_DESCRIPTOR.as_ptr(),
-        ]
-    }
-
-    pub fn start(&self, serial_number: &CStr16) -> Result {
-        let string_descriptors = Self::build_string_descriptors(serial_number);
-
+    /// The firmware refers to `descriptors` for as long as the device runs.
+    pub fn start(&self, descriptors: &UsbDescriptors) -> Result {
         unsafe {
             (self.0.start)(
-                &DEVICE_DESCRIPTOR,
-                CONFIG_DESCRIPTOR_TREES.as_ptr(),
-                &DEVICE_QUALIFIER,
+                &descriptors.device,
+                descriptors.config_descriptor_trees.as_ptr(),
+                &descriptors.device_qualifier,
                 &BINARY_OBJECT_STORE,
-                5,
-                string_descriptors.as_ptr(),
+                descriptors.string_descriptor_count(),
+                descriptors.string_descriptors(),
             )
         }
         .to_result()
@@ -352,27 +448,52 @@ impl EfiUsbDevice {
                         Ok(EfiUsbDeviceEvent::OutData(test))
                     }
                     (ENDPOINT_OUT, UsbDeviceTransferStatus::Cancelled) => {
-                        Ok(EfiUsbDeviceEvent::NoEvent)
+                        Ok(EfiUsbDeviceEvent::OutError)
                     }
                     (ENDPOINT_OUT, UsbDeviceTransferStatus::CompleteError) => {
-                        Ok(EfiUsbDeviceEvent::NoEvent)
+                        Ok(EfiUsbDeviceEvent::OutError)
                     }
                     (ENDPOINT_IN, UsbDeviceTransferStatus::Active) => {
                         Ok(EfiUsbDeviceEvent::NoEvent)
                     }
                     (ENDPOINT_IN, UsbDeviceTransferStatus::CompleteOK) => {
-                        Ok(EfiUsbDeviceEvent::NoEvent)
+                        Ok(EfiUsbDeviceEvent::InComplete)
                     }
                     (ENDPOINT_IN, UsbDeviceTransferStatus::Cancelled) => {
-                        Ok(EfiUsbDeviceEvent::NoEvent)
+                        Ok(EfiUsbDeviceEvent::InError)
                     }
                     (ENDPOINT_IN, UsbDeviceTransferStatus::CompleteError) => {
-                        Ok(EfiUsbDeviceEvent::NoEvent)
+                        Ok(EfiUsbDeviceEvent::InError)
+                    }
+                    (SERIAL_ENDPOINT_IN, UsbDeviceTransferStatus::CompleteOK) => {
+                        Ok(EfiUsbDeviceEvent::SerialInComplete)
+                    }
+                    (SERIAL_ENDPOINT_IN, UsbDeviceTransferStatus::Cancelled) => {
+                        Ok(EfiUsbDeviceEvent::SerialInError)
+                    }
+                    (SERIAL_ENDPOINT_IN, UsbDeviceTransferStatus::CompleteError) => {
+                        Ok(EfiUsbDeviceEvent::SerialInError)
                     }
                     _ => Ok(EfiUsbDeviceEvent::NoEvent),
                 }
             }
-            UsbDeviceEvent::OemEvent => Ok(EfiUsbDeviceEvent::NoEvent),
+            UsbDeviceEvent::OemEvent => {
+                let oem_data = unsafe { event_data.oem_data };
+                // The payload is only valid until the next call, so it's
+                // copied out.
+                let data = if oem_data.context.is_null() {
+                    Vec::new()
+                } else {
+                    unsafe { slice::from_raw_parts(oem_data.context, oem_data.size as usize) }
+                        .to_vec()
+                };
+
+                Ok(EfiUsbDeviceEvent::Oem(OemEvent {
+                    guid: oem_data.guid,
+                    version: oem_data.version,
+                    data,
+                }))
+            }
         }
     }
 
@@ -386,28 +507,600 @@ impl EfiUsbDevice {
         unsafe { (self.0.free_transfer_buffer)(ptr) }.to_result()
     }
 
-    pub fn set_endpoint_stall_state(&self, state: bool) -> Result {
-        unsafe { (self.0.set_endpoint_stall_state)(0, state) }.to_result()
+    pub fn revision(&self) -> u64 {
+        self.0.revision
+    }
+
+    // Only present from EFI_USB_DEVICE_PROTOCOL_REVISION_1_1 on.
+    fn abort_xfer(&self, endpoint: u8) -> Result {
+        unsafe { (self.0.abort_xfer)(endpoint) }.to_result()
+    }
+
+    // Only present from EFI_USB_DEVICE_PROTOCOL_REVISION_1_1 on.
+    fn set_endpoint_stall_state(&self, endpoint: u8, state: bool) -> Result {
+        unsafe { (self.0.set_endpoint_stall_state)(endpoint, state) }.to_result()
     }
 
     pub fn stop(&self) -> Result {
         unsafe { (self.0.stop)() }.to_result()
     }
 
-    pub fn start_ex(&self, serial_number: &CStr16) -> Result {
-        let string_descriptors = Self::build_string_descriptors(serial_number);
-
+    // Only present from EFI_USB_DEVICE_PROTOCOL_REVISION_1_2 on.
+    fn start_ex(&self, descriptors: &UsbDescriptors) -> Result {
         let device_descriptor_set = UsbDeviceDescriptorSet {
-            device_descriptor: &DEVICE_DESCRIPTOR,
-            config_descriptor_trees: CONFIG_DESCRIPTOR_TREES.as_ptr(),
-            superspeed_device_descriptor: &SUPERSPEED_DEVICE_DESCRIPTOR,
-            superspeed_config_descriptor_trees: SUPERSPEED_CONFIG_DESCRIPTOR_TREES.as_ptr(),
-            device_qualifier_descriptor: &DEVICE_QUALIFIER,
+            device_descriptor: &descriptors.device,
+            config_descriptor_trees: descriptors.config_descriptor_trees.as_ptr(),
+            superspeed_device_descriptor: &descriptors.superspeed_device,
+            superspeed_config_descriptor_trees: descriptors
+                .superspeed_config_descriptor_trees
+                .as_ptr(),
+            device_qualifier_descriptor: &descriptors.device_qualifier,
             binary_object_store: &BINARY_OBJECT_STORE,
-            string_descriptor_count: 5,
-            string_descriptors: string_descriptors.as_ptr(),
+            string_descriptor_count: descriptors.string_descriptor_count(),
+            string_descriptors: descriptors.string_descriptors(),
         };
 
         unsafe { (self.0.start_ex)(&device_descriptor_set) }.to_result()
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionState {
+    /// No host attached, waiting for enumeration.
+    Idle,
+    /// Command endpoint armed, waiting for the next command.
+    Command,
+    /// A download or upload is moving data.
+    DataPhase,
+    /// The host went away or re-enumerated during a data phase.
+    Aborted,
+}
+
+const MIN_TRANSFER_BUFFER_SIZE: usize = 64;
+
+/// A transfer buffer handed out by the session's buffer pool.
+pub struct TransferBuffer {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl TransferBuffer {
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.size) }
+    }
+}
+
+// Buffers are kept per power-of-two size class and reused, as the firmware
+// allocator is never given them back until the session ends. IN buffers
+// are owned by the pool from the moment they're queued and become free
+// again once their transfer completes. Only the transfer at the front of
+// the IN queue is ever submitted, so responses go out one at a time and
+// in order.
+#[derive(Default)]
+struct TransferBufferPool {
+    free: Vec<TransferBuffer>,
+    in_queue: VecDeque<(TransferBuffer, usize)>,
+}
+
+impl TransferBufferPool {
+    fn size_class(size: usize) -> usize {
+        size.max(MIN_TRANSFER_BUFFER_SIZE).next_power_of_two()
+    }
+
+    fn take(&mut self, size: usize) -> Option<TransferBuffer> {
+        let index = self.free.iter().position(|buffer| buffer.size == size)?;
+        Some(self.free.swap_remove(index))
+    }
+
+    fn complete_in(&mut self) {
+        if let Some((buffer, _)) = self.in_queue.pop_front() {
+            self.free.push(buffer);
+        }
+    }
+
+    fn cancel_in(&mut self) {
+        let cancelled = self.in_queue.drain(..).map(|(buffer, _)| buffer);
+        self.free.extend(cancelled);
+    }
+}
+
+const MAX_OEM_EVENTS: usize = 16;
+
+const SERIAL_TRANSFER_SIZE: usize = 4096;
+
+// In 100ns units, i.e. 1ms.
+const USB_POLL_INTERVAL: u64 = 10_000;
+
+// Poll timer ticks to wait for the completion of an aborted OUT transfer.
+const ABORT_DRAIN_POLLS: usize = 100;
+
+/// Link information gathered from events, shared with the getvar provider.
+#[derive(Default)]
+struct UsbStatus {
+    /// Whether the device was last started with SuperSpeed descriptors.
+    superspeed: Cell<Option<bool>>,
+    oem_events: RefCell<VecDeque<(Guid, u64)>>,
+}
+
+/// The CDC-ACM side of a composite configuration, streaming mirrored
+/// console output to the host one transfer at a time.
+struct SerialConsole {
+    mirror: ConOutMirror,
+    in_flight: RefCell<Option<TransferBuffer>>,
+}
+
+/// Tracks the fastboot session on top of the USB device, following the
+/// connection state reported through `handle_event()`.
+pub struct UsbSession<'a> {
+    device: &'a ScopedProtocol<EfiUsbDevice>,
+    revision: u64,
+    poll_timer: Event,
+    status: Rc<UsbStatus>,
+    state: Cell<SessionState>,
+    connected: Cell<bool>,
+    receive_pending: Cell<bool>,
+    deferred_events: RefCell<VecDeque<EfiUsbDeviceEvent>>,
+    buffers: RefCell<TransferBufferPool>,
+    serial: Option<SerialConsole>,
+    descriptors: Rc<UsbDescriptors>,
+    active_descriptors: RefCell<Rc<UsbDescriptors>>,
+}
+
+impl<'a> UsbSession<'a> {
+    /// Starts the device with `descriptors`, which it returns to whenever
+    /// another function is done with it. When they include the CDC-ACM
+    /// interface, console output is mirrored to it.
+    pub fn new(
+        device: &'a ScopedProtocol<EfiUsbDevice>,
+        descriptors: UsbDescriptors,
+    ) -> Result<Self> {
+        let poll_timer =
+            unsafe { boot::create_event(EventType::TIMER, Tpl::CALLBACK, None, None)? };
+        boot::set_timer(&poll_timer, TimerTrigger::Periodic(USB_POLL_INTERVAL))?;
+
+        let revision = device.revision();
+        info!("usb device protocol revision {revision:#x}");
+
+        let serial_console = descriptors.serial_console;
+        let descriptors = Rc::new(descriptors);
+
+        let session = Self {
+            device,
+            revision,
+            poll_timer,
+            status: Rc::default(),
+            state: Cell::new(SessionState::Idle),
+            connected: Cell::new(false),
+            receive_pending: Cell::new(false),
+            deferred_events: RefCell::default(),
+            buffers: RefCell::default(),
+            serial: serial_console
+                .then(ConOutMirror::install)
+                .flatten()
+                .map(|mirror| SerialConsole {
+                    mirror,
+                    in_flight: RefCell::new(None),
+                }),
+            active_descriptors: RefCell::new(descriptors.clone()),
+            descriptors,
+        };
+        session.start(&session.descriptors)?;
+
+        Ok(session)
+    }
+
+    fn require_revision(&self, revision: u64) -> Result {
+        if self.revision < revision {
+            return Err(Status::UNSUPPORTED.into());
+        }
+
+        Ok(())
+    }
+
+    // Firmware that claims start_ex but turns it down still gets the
+    // high-speed descriptors through start.
+    fn start(&self, descriptors: &UsbDescriptors) -> Result {
+        let started = self
+            .require_revision(EFI_USB_DEVICE_PROTOCOL_REVISION_1_2)
+            .and_then(|()| self.device.start_ex(descriptors));
+
+        let superspeed = match started {
+            Ok(()) => {
+                info!("usb device started with SuperSpeed descriptors");
+                true
+            }
+            Err(err) if err.status() == Status::UNSUPPORTED => {
+                info!("start_ex not supported, using high-speed descriptors");
+                self.device.start(descriptors)?;
+                false
+            }
+            Err(err) => return Err(err),
+        };
+        self.status.superspeed.set(Some(superspeed));
+
+        Ok(())
+    }
+
+    /// Stops the device and enumerates it again as the function described
+    /// by `descriptors`. Every transfer in flight is dropped and the session
+    /// starts over as disconnected.
+    pub fn reenumerate(&self, descriptors: Rc<UsbDescriptors>) -> Result {
+        if let Err(err) = self.device.stop() {
+            info!("failed to stop usb device: {:?}", err.status());
+        }
+
+        self.connection_changed(false);
+        self.set_state(SessionState::Idle);
+
+        self.start(&descriptors)?;
+        *self.active_descriptors.borrow_mut() = descriptors;
+
+        Ok(())
+    }
+
+    /// Enumerates again with the descriptors the session was started with.
+    pub fn restore(&self) -> Result {
+        self.reenumerate(self.descriptors.clone())
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state.get()
+    }
+
+    fn set_state(&self, state: SessionState) {
+        if self.state.get() != state {
+            info!("usb session: {:?} -> {:?}", self.state.get(), state);
+            self.state.set(state);
+        }
+    }
+
+    fn connection_changed(&self, connected: bool) {
+        self.connected.set(connected);
+        self.receive_pending.set(false);
+        self.buffers.borrow_mut().cancel_in();
+        self.serial_in_done(false);
+
+        let state = match self.state.get() {
+            SessionState::DataPhase | SessionState::Aborted => SessionState::Aborted,
+            _ if connected => SessionState::Command,
+            _ => SessionState::Idle,
+        };
+        self.set_state(state);
+    }
+
+    /// Returns the next USB event. When there's none pending, this sleeps
+    /// until the next tick of the poll timer and returns `NoEvent`, giving
+    /// the caller a chance to run its own periodic work.
+    pub fn wait_event(&self) -> Result<EfiUsbDeviceEvent> {
+        self.flush_serial();
+
+        let event = self.handle_event()?;
+
+        if let EfiUsbDeviceEvent::NoEvent = event {
+            let mut events = [unsafe { self.poll_timer.unsafe_clone() }];
+            boot::wait_for_event(&mut events).discard_errdata()?;
+        }
+
+        Ok(event)
+    }
+
+    fn handle_event(&self) -> Result<EfiUsbDeviceEvent> {
+        let deferred = self.deferred_events.borrow_mut().pop_front();
+        let event = match deferred {
+            Some(event) => event,
+            None => self.device.handle_event()?,
+        };
+
+        let event = match event {
+            EfiUsbDeviceEvent::SerialInComplete => {
+                self.serial_in_done(false);
+                EfiUsbDeviceEvent::NoEvent
+            }
+            EfiUsbDeviceEvent::SerialInError => {
+                self.serial_in_done(true);
+                EfiUsbDeviceEvent::NoEvent
+            }
+            event => event,
+        };
+
+        match event {
+            EfiUsbDeviceEvent::Connected => self.connection_changed(true),
+            EfiUsbDeviceEvent::Disconnected => self.connection_changed(false),
+            EfiUsbDeviceEvent::OutData(_) => self.receive_pending.set(false),
+            EfiUsbDeviceEvent::OutError => {
+                self.receive_pending.set(false);
+                self.transfer_failed(ENDPOINT_OUT);
+            }
+            EfiUsbDeviceEvent::InComplete => {
+                self.buffers.borrow_mut().complete_in();
+                let _ = self.submit_in();
+            }
+            EfiUsbDeviceEvent::InError => {
+                self.transfer_failed(ENDPOINT_IN);
+                self.buffers.borrow_mut().complete_in();
+                let _ = self.submit_in();
+            }
+            EfiUsbDeviceEvent::Oem(ref oem_event) => self.oem_event(oem_event),
+            _ => {}
+        }
+
+        Ok(event)
+    }
+
+    fn oem_event(&self, event: &OemEvent) {
+        info!(
+            "usb oem event {} version {:#x}, {} bytes",
+            event.guid,
+            event.version,
+            event.data.len()
+        );
+
+        let mut oem_events = self.status.oem_events.borrow_mut();
+        if oem_events.len() == MAX_OEM_EVENTS {
+            oem_events.pop_front();
+        }
+        oem_events.push_back((event.guid, event.version));
+    }
+
+    // Failures aren't logged here, as the log would be mirrored right back
+    // onto the endpoint that failed.
+    fn serial_in_done(&self, failed: bool) {
+        let Some(serial) = &self.serial else {
+            return;
+        };
+
+        if let Some(buffer) = serial.in_flight.borrow_mut().take() {
+            self.release_buffer(buffer);
+        }
+
+        if failed {
+            let _ = self.set_endpoint_stall_state(SERIAL_ENDPOINT_IN, false);
+        }
+    }
+
+    fn flush_serial(&self) {
+        let Some(serial) = &self.serial else {
+            return;
+        };
+
+        if !self.connected.get()
+            || !self.active_descriptors.borrow().serial_console
+            || serial.in_flight.borrow().is_some()
+            || serial.mirror.is_empty()
+        {
+            return;
+        }
+
+        let Ok(mut buffer) = self.acquire_buffer(SERIAL_TRANSFER_SIZE) else {
+            return;
+        };
+
+        let len = serial.mirror.read(buffer.as_mut_slice());
+        match self.device.send(SERIAL_ENDPOINT_IN, len, buffer.ptr) {
+            Ok(()) => *serial.in_flight.borrow_mut() = Some(buffer),
+            Err(_) => self.release_buffer(buffer),
+        }
+    }
+
+    /// Variables reporting the link speed and recent OEM events.
+    pub fn variables(&self) -> UsbVariables {
+        UsbVariables(self.status.clone())
+    }
+
+    pub fn acquire_buffer(&self, size: usize) -> Result<TransferBuffer> {
+        let size = TransferBufferPool::size_class(size);
+
+        if let Some(buffer) = self.buffers.borrow_mut().take(size) {
+            return Ok(buffer);
+        }
+
+        let ptr = self.device.allocate_transfer_buffer(size)?;
+        Ok(TransferBuffer { ptr, size })
+    }
+
+    pub fn release_buffer(&self, buffer: TransferBuffer) {
+        self.buffers.borrow_mut().free.push(buffer);
+    }
+
+    // Submits the transfer at the front of the IN queue, dropping any that
+    // the controller refuses so the ones behind them still go out.
+    fn submit_in(&self) -> Result {
+        let mut buffers = self.buffers.borrow_mut();
+
+        loop {
+            let Some((buffer, len)) = buffers.in_queue.front() else {
+                return Ok(());
+            };
+
+            match self.device.send(ENDPOINT_IN, *len, buffer.ptr) {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    info!("failed to send IN transfer: {:?}", err.status());
+                    buffers.complete_in();
+                    if buffers.in_queue.is_empty() {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues `len` bytes of `buffer` on the IN endpoint behind any
+    /// transfer still in flight. The buffer goes back to the pool once the
+    /// transfer completes.
+    pub fn send_in(&self, buffer: TransferBuffer, len: usize) -> Result {
+        let len = len.min(buffer.size);
+
+        let idle = {
+            let mut buffers = self.buffers.borrow_mut();
+            buffers.in_queue.push_back((buffer, len));
+            buffers.in_queue.len() == 1
+        };
+
+        if idle {
+            self.submit_in()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether every queued IN transfer has completed.
+    pub fn in_idle(&self) -> bool {
+        self.buffers.borrow().in_queue.is_empty()
+    }
+
+    /// Queues `buffer` on the OUT endpoint to receive up to `len` bytes.
+    pub fn receive(&self, buffer: &TransferBuffer, len: usize) -> Result {
+        self.device
+            .send(ENDPOINT_OUT, len.min(buffer.size), buffer.ptr)?;
+        self.receive_pending.set(true);
+
+        Ok(())
+    }
+
+    /// Whether an OUT transfer is queued and not yet completed.
+    pub fn receive_pending(&self) -> bool {
+        self.receive_pending.get()
+    }
+
+    /// Cancels the transfer pending on `endpoint`. Cancelling IN also drops
+    /// every response still queued behind it.
+    pub fn abort(&self, endpoint: u8) -> Result {
+        self.require_revision(EFI_USB_DEVICE_PROTOCOL_REVISION_1_1)?;
+
+        let result = self.device.abort_xfer(endpoint);
+
+        if endpoint == ENDPOINT_IN {
+            self.buffers.borrow_mut().cancel_in();
+        } else if self.receive_pending.replace(false) && result.is_ok() {
+            self.drain_out_completion();
+        }
+
+        result
+    }
+
+    // The aborted transfer can still complete, either as cancelled or with
+    // data that arrived just before. That completion is consumed here, so it
+    // isn't taken for one of the next transfer queued on the endpoint.
+    // Other events seen meanwhile are kept for handle_event().
+    fn drain_out_completion(&self) {
+        for _ in 0..ABORT_DRAIN_POLLS {
+            match self.device.handle_event() {
+                Ok(EfiUsbDeviceEvent::OutData(_) | EfiUsbDeviceEvent::OutError) | Err(_) => return,
+                Ok(EfiUsbDeviceEvent::NoEvent) => {
+                    let mut events = [unsafe { self.poll_timer.unsafe_clone() }];
+                    if boot::wait_for_event(&mut events).is_err() {
+                        return;
+                    }
+                }
+                Ok(event @ (EfiUsbDeviceEvent::Connected | EfiUsbDeviceEvent::Disconnected)) => {
+                    self.deferred_events.borrow_mut().push_back(event);
+                    return;
+                }
+                Ok(event) => self.deferred_events.borrow_mut().push_back(event),
+            }
+        }
+    }
+
+    pub fn set_endpoint_stall_state(&self, endpoint: u8, state: bool) -> Result {
+        self.require_revision(EFI_USB_DEVICE_PROTOCOL_REVISION_1_1)?;
+        self.device.set_endpoint_stall_state(endpoint, state)
+    }
+
+    // A transfer that ended in error may leave its endpoint halted, so the
+    // stall is cleared before anything else is queued on it.
+    fn transfer_failed(&self, endpoint: u8) {
+        info!("transfer on endpoint {endpoint:#x} failed");
+
+        if let Err(err) = self.set_endpoint_stall_state(endpoint, false) {
+            info!(
+                "failed to clear stall on endpoint {endpoint:#x}: {:?}",
+                err.status()
+            );
+        }
+    }
+
+    pub fn begin_data_phase(&self) {
+        if self.state.get() == SessionState::Command {
+            self.set_state(SessionState::DataPhase);
+        }
+    }
+
+    pub fn end_data_phase(&self) {
+        if self.state.get() == SessionState::DataPhase {
+            self.set_state(SessionState::Command);
+        }
+    }
+
+    /// Leaves the aborted state once the interrupted command has unwound,
+    /// returning the state the session continues in.
+    pub fn recover(&self) -> SessionState {
+        if self.state.get() == SessionState::Aborted {
+            self.set_state(if self.connected.get() {
+                SessionState::Command
+            } else {
+                SessionState::Idle
+            });
+        }
+
+        self.state.get()
+    }
+}
+
+impl Drop for UsbSession<'_> {
+    fn drop(&mut self) {
+        self.serial_in_done(false);
+
+        let mut buffers = self.buffers.borrow_mut();
+        buffers.cancel_in();
+
+        for buffer in buffers.free.drain(..) {
+            let _ = self.device.free_transfer_buffer(buffer.ptr);
+        }
+
+        let _ = boot::close_event(unsafe { self.poll_timer.unsafe_clone() });
+    }
+}
+
+impl Deref for UsbSession<'_> {
+    type Target = EfiUsbDevice;
+
+    fn deref(&self) -> &EfiUsbDevice {
+        self.device
+    }
+}
+
+pub struct UsbVariables(Rc<UsbStatus>);
+
+impl VariableProvider for UsbVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            // None of the events carries the negotiated speed. What is known
+            // is the ceiling: without SuperSpeed descriptors the device can't
+            // enumerate faster than high speed.
+            "usb-speed" => match self.0.superspeed.get() {
+                Some(false) => Some("high-speed or lower".into()),
+                Some(true) => Some("unknown, SuperSpeed capable".into()),
+                None => Some("unknown".into()),
+            },
+            "usb-oem-events" => {
+                let events: Vec<_> = self
+                    .0
+                    .oem_events
+                    .borrow()
+                    .iter()
+                    .map(|(guid, version)| format!("{guid}/{version:#x}"))
+                    .collect();
+                Some(events.join(","))
+            }
+            _ => None,
+        }
+    }
+
+    fn all(&self) -> Vec<(String, String)> {
+        ["usb-speed", "usb-oem-events"]
+            .into_iter()
+            .filter_map(|name| Some((name.into(), self.get(name)?)))
+            .collect()
+    }
+}
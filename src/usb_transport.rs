@@ -0,0 +1,205 @@
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use log::info;
+
+use crate::error::{FastbootError, FastbootResult};
+use crate::transport::{FastbootTransport, FillFn, MAX_RESPONSE_SIZE};
+use crate::usb_device::{
+    EfiUsbDeviceEvent, SessionState, TransferBuffer, UsbSession, ENDPOINT_IN, ENDPOINT_OUT,
+};
+
+const COMMAND_BUFFER_SIZE: usize = 1024 * 1024;
+const DOWNLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+pub(crate) fn wait_for_in_complete(usb_device: &UsbSession) -> FastbootResult {
+    while !usb_device.in_idle() {
+        match usb_device.wait_event()? {
+            EfiUsbDeviceEvent::InError => return Err(FastbootError::TransferFailed),
+            EfiUsbDeviceEvent::Connected | EfiUsbDeviceEvent::Disconnected => {
+                return Err(FastbootError::Disconnected)
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(())
+}
+
+/// Fastboot over the bulk endpoints of a [`UsbSession`].
+pub(crate) struct UsbTransport<'a> {
+    usb_device: &'a UsbSession<'a>,
+    command_buffer: TransferBuffer,
+}
+
+impl<'a> UsbTransport<'a> {
+    pub(crate) fn new(usb_device: &'a UsbSession<'a>) -> FastbootResult<Self> {
+        let command_buffer = usb_device.acquire_buffer(COMMAND_BUFFER_SIZE)?;
+
+        Ok(Self {
+            usb_device,
+            command_buffer,
+        })
+    }
+
+    pub(crate) fn close(self) {
+        self.usb_device.release_buffer(self.command_buffer);
+    }
+
+    fn queue_command_buffer(&self) {
+        if self.usb_device.receive_pending() {
+            return;
+        }
+
+        if let Err(err) = self
+            .usb_device
+            .receive(&self.command_buffer, COMMAND_BUFFER_SIZE)
+        {
+            info!("failed to queue command buffer: {:?}", err.status());
+        }
+    }
+}
+
+impl FastbootTransport for UsbTransport<'_> {
+    fn receive_command(&self) -> FastbootResult<&[u8]> {
+        if self.usb_device.recover() == SessionState::Command {
+            self.queue_command_buffer();
+        }
+
+        loop {
+            let event = self.usb_device.wait_event()?;
+            match event {
+                EfiUsbDeviceEvent::NoEvent => {}
+                EfiUsbDeviceEvent::InComplete => {}
+                EfiUsbDeviceEvent::InError => info!("failed to send response"),
+                EfiUsbDeviceEvent::Oem(_) => {}
+                // The session has already cleared any stall on the endpoint,
+                // so a failed or cancelled command transfer is simply
+                // re-armed.
+                EfiUsbDeviceEvent::OutError if self.usb_device.state() == SessionState::Command => {
+                    self.queue_command_buffer()
+                }
+                // Also covers re-enumeration while already connected, which
+                // drops whatever transfer was queued before.
+                EfiUsbDeviceEvent::Connected => self.queue_command_buffer(),
+                EfiUsbDeviceEvent::OutData(_)
+                    if self.usb_device.state() != SessionState::Command =>
+                {
+                    info!("ignoring data outside of a session");
+                }
+                EfiUsbDeviceEvent::OutData(data) => return Ok(data),
+                _ => info!("{:#?}", event),
+            }
+        }
+    }
+
+    fn send_packet(&self, packet: &[u8]) -> FastbootResult {
+        let mut buf = self.usb_device.acquire_buffer(MAX_RESPONSE_SIZE)?;
+        buf.as_mut_slice()[..packet.len()].copy_from_slice(packet);
+
+        Ok(self.usb_device.send_in(buf, packet.len())?)
+    }
+
+    fn receive_data(&self, target: &mut [u8]) -> FastbootResult {
+        let usb_device = self.usb_device;
+        let receive_buffer_size = target.len().min(DOWNLOAD_CHUNK_SIZE);
+        let receive_buffer = usb_device.acquire_buffer(DOWNLOAD_CHUNK_SIZE)?;
+
+        usb_device.begin_data_phase();
+
+        let mut offset = 0;
+        let mut result = usb_device
+            .receive(&receive_buffer, receive_buffer_size)
+            .map_err(FastbootError::from);
+
+        while result.is_ok() && offset < target.len() {
+            match usb_device.wait_event() {
+                Err(err) => result = Err(err.into()),
+                Ok(EfiUsbDeviceEvent::OutData(data)) => {
+                    let len = data.len().min(target.len() - offset);
+                    target[offset..offset + len].copy_from_slice(&data[..len]);
+                    offset += len;
+
+                    if offset < target.len() {
+                        let next_chunk = (target.len() - offset).min(receive_buffer_size);
+                        result = usb_device
+                            .receive(&receive_buffer, next_chunk)
+                            .map_err(FastbootError::from);
+                    }
+                }
+                Ok(EfiUsbDeviceEvent::OutError) => result = Err(FastbootError::TransferFailed),
+                Ok(_) => {}
+            }
+
+            if usb_device.state() == SessionState::Aborted {
+                result = Err(FastbootError::Disconnected);
+            }
+        }
+
+        // The receive buffer can't go back to the pool while the controller
+        // may still write into it.
+        if usb_device.receive_pending() {
+            if let Err(err) = usb_device.abort(ENDPOINT_OUT) {
+                info!("failed to abort OUT transfer: {:?}", err.status());
+            }
+        }
+
+        usb_device.end_data_phase();
+        usb_device.release_buffer(receive_buffer);
+
+        result
+    }
+
+    fn send_data(&self, size: u64, fill: &mut FillFn) -> FastbootResult {
+        let usb_device = self.usb_device;
+        let chunk_size = (size as usize).min(UPLOAD_CHUNK_SIZE);
+        usb_device.begin_data_phase();
+
+        let mut offset = 0;
+        let result = loop {
+            if offset == size {
+                break Ok(());
+            }
+
+            let mut send_buffer = match usb_device.acquire_buffer(chunk_size) {
+                Ok(send_buffer) => send_buffer,
+                Err(err) => break Err(err.into()),
+            };
+
+            let len = (size - offset).min(chunk_size as u64) as usize;
+            if let Err(err) = fill(offset, &mut send_buffer.as_mut_slice()[..len]) {
+                usb_device.release_buffer(send_buffer);
+                break Err(err);
+            }
+
+            let sent = usb_device
+                .send_in(send_buffer, len)
+                .map_err(FastbootError::from)
+                .and_then(|_| wait_for_in_complete(usb_device));
+            if let Err(err) = sent {
+                break Err(err);
+            }
+
+            offset += len as u64;
+        };
+
+        if result.is_err() && !usb_device.in_idle() {
+            if let Err(err) = usb_device.abort(ENDPOINT_IN) {
+                info!("failed to abort IN transfer: {:?}", err.status());
+            }
+        }
+
+        usb_device.end_data_phase();
+
+        result
+    }
+
+    fn flush(&self) -> FastbootResult {
+        wait_for_in_complete(self.usb_device)
+    }
+
+    fn can_respond(&self) -> bool {
+        self.usb_device.state() == SessionState::Command
+    }
+}